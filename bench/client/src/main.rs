@@ -13,6 +13,7 @@ extern crate env_logger;
 extern crate simple_stream as ss;
 
 
+use std::env;
 use std::thread;
 use std::net::TcpStream;
 use std::os::unix::io::IntoRawFd;
@@ -35,7 +36,9 @@ struct Client {
 fn main() {
     env_logger::init().unwrap();
 
-    let tcp_stream = TcpStream::connect("127.0.0.1:1337").unwrap();
+    // Address of the server, so servers on different backends can be run side by side
+    let addr = env::args().nth(1).unwrap_or("127.0.0.1:1337".to_owned());
+    let tcp_stream = TcpStream::connect(&addr[..]).unwrap();
     let fd = tcp_stream.into_raw_fd();
     let socket = Socket::new(fd);
     let client = Client {
@@ -8,27 +8,27 @@
 
 #[macro_use]
 extern crate log;
-extern crate libc;
 extern crate env_logger;
 extern crate hydrogen;
 extern crate simple_stream as ss;
 
 
-use std::mem;
 use std::io::Error;
 use std::sync::Arc;
 use std::cell::UnsafeCell;
 use std::os::unix::io::{RawFd, AsRawFd};
 
-use hydrogen::{Stream as HydrogenStream, HydrogenSocket};
-use ss::frame::Frame;
+use hydrogen::{Stream as HydrogenStream, HydrogenSocket, ListenerId};
+use ss::frame::{Frame, FrameBuilder};
 use ss::frame::simple::{SimpleFrame, SimpleFrameBuilder};
-use ss::{Socket, Plain, NonBlocking, SocketOptions};
+use ss::{Socket, Plain, NonBlocking};
 
 
 
 struct Stream {
-    inner: Plain<Socket, SimpleFrameBuilder>
+    inner: Plain<Socket, SimpleFrameBuilder>,
+    /// Received by io_uring, but not yet a whole frame
+    rx_buf: Vec<u8>
 }
 
 impl HydrogenStream for Stream {
@@ -51,6 +51,19 @@ impl HydrogenStream for Stream {
     fn shutdown(&mut self) -> Result<(), Error> {
         self.inner.shutdown()
     }
+    // Only taken up by Backend::IoUring, which then receives and sends itself
+    fn completion_io(&self) -> bool { true }
+    fn decode(&mut self, buf: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+        self.rx_buf.extend_from_slice(buf);
+        let mut ret_buf = Vec::<Vec<u8>>::new();
+        while let Some(frame) = SimpleFrameBuilder::from_bytes(&mut self.rx_buf) {
+            ret_buf.push(frame.payload());
+        }
+        Ok(ret_buf)
+    }
+    fn encode(&mut self, buf: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(SimpleFrame::new(buf).to_bytes())
+    }
 }
 
 impl AsRawFd for Stream {
@@ -59,28 +72,19 @@ impl AsRawFd for Stream {
 
 struct Server;
 impl hydrogen::Handler for Server {
-    fn on_server_created(&mut self, fd: RawFd) {
-        let mut socket = Socket::new(fd);
-        let _ = socket.set_reuseaddr(true);
-    }
-
-    fn on_new_connection(&mut self, fd: RawFd) -> Arc<UnsafeCell<hydrogen::Stream>> {
-        let mut socket = Socket::new(fd);
-        let _ = socket.set_nonblocking();
-        let _ = socket.set_keepalive(true);
-
-        unsafe {
-            let opt = 1;
-            let _ = libc::setsockopt(socket.as_raw_fd(),
-                                              libc::IPPROTO_TCP,
-                                              libc::TCP_NODELAY,
-                                              &opt as *const _ as *const libc::c_void,
-                                              mem::size_of::<libc::c_int>() as u32);
-        }
+    #[allow(unused_variables)]
+    fn on_server_created(&mut self, fd: RawFd) { }
 
+    #[allow(unused_variables)]
+    fn on_new_connection(&mut self, fd: RawFd, listener: ListenerId)
+        -> Arc<UnsafeCell<hydrogen::Stream>>
+    {
+        // Already non-blocking, with the options from Config::connection set
+        let socket = Socket::new(fd);
         let plain_stream = Plain::<Socket, SimpleFrameBuilder>::new(socket);
         let stream = Stream {
-            inner: plain_stream
+            inner: plain_stream,
+            rx_buf: Vec::new()
         };
 
         Arc::new(UnsafeCell::new(stream))
@@ -103,10 +107,21 @@ impl hydrogen::Handler for Server {
 
 fn main() {
     env_logger::init().unwrap();
-    hydrogen::begin(Box::new(Server), hydrogen::Config {
-        addr: "127.0.0.1".to_string(),
-        port: 1337,
-        max_threads: 2,
-        pre_allocated: 100
-    });
+
+    // HYDROGEN_ADDR, HYDROGEN_PORT, HYDROGEN_MAX_THREADS, etc. override these. Built with
+    // --features io-uring, HYDROGEN_BACKEND=io_uring runs it on io_uring instead of epoll,
+    // receiving and sending through the ring as well.
+    let cfg = hydrogen::Config::builder()
+        .addr("127.0.0.1:1337")
+        .max_threads(2)
+        .pre_allocated(100)
+        .backlog(1024)
+        .connection(hydrogen::ConnectionOptions::default().nodelay(true).keepalive(true))
+        .handle_signals(true)
+        .env("HYDROGEN_")
+        .and_then(|b| b.build())
+        .unwrap();
+
+    let server = hydrogen::begin(Box::new(Server), cfg).unwrap();
+    server.join().unwrap();
 }
@@ -0,0 +1,239 @@
+// Copyright 2015 Nathan Sizemore <nathanrsizemore@gmail.com>
+//
+// This Source Code Form is subject to the terms of the
+// Mozilla Public License, v. 2.0. If a copy of the MPL was not
+// distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+
+
+use std::env;
+use std::mem;
+use std::io::{Error, ErrorKind};
+use std::os::unix::io::RawFd;
+
+use libc;
+
+use socket::Listener;
+
+
+// First fd passed by the service manager, after stdin, stdout and stderr
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Takes ownership of the listening sockets passed through the `LISTEN_FDS` protocol, each
+/// with its name from `LISTEN_FDNAMES`, if it was given one.
+///
+/// Returns an empty list if this process was not socket activated. The `LISTEN_*` variables
+/// are left in the environment, as changing it is unsound while other threads may read it.
+/// Child processes ignore them since `LISTEN_PID` is not theirs, but a caller that wants
+/// them gone has to remove them before starting any thread.
+pub fn listeners() -> Result<Vec<(Listener, Option<String>)>, Error> {
+    adopt(SD_LISTEN_FDS_START)
+}
+
+/// Adopts the fds from `first_fd` on, as `LISTEN_FDS` describes them.
+fn adopt(first_fd: RawFd) -> Result<Vec<(Listener, Option<String>)>, Error> {
+    let pid = env::var("LISTEN_PID").ok();
+    let num_fds = env::var("LISTEN_FDS").ok();
+    let names = env::var("LISTEN_FDNAMES").ok();
+
+    let (pid, num_fds) = match (pid, num_fds) {
+        (Some(pid), Some(num_fds)) => (pid, num_fds),
+        _ => return Ok(Vec::new())
+    };
+
+    // The variables were meant for some other process
+    let pid = parse_env("LISTEN_PID", &pid)? as libc::pid_t;
+    if pid != unsafe { libc::getpid() } {
+        debug!("LISTEN_PID {} is not ours, ignoring LISTEN_FDS", pid);
+        return Ok(Vec::new());
+    }
+
+    let num_fds = parse_env("LISTEN_FDS", &num_fds)? as RawFd;
+    let names: Vec<&str> = match names {
+        Some(ref n) => n.split(':').collect(),
+        None => Vec::new()
+    };
+
+    let mut listeners = Vec::<(Listener, Option<String>)>::with_capacity(num_fds as usize);
+    for x in 0..num_fds {
+        let fd = first_fd + x;
+        let name = match names.get(x as usize) {
+            Some(n) if !n.is_empty() => Some(n.to_string()),
+            _ => None
+        };
+        debug!("Adopting socket activated fd: {}    name: {:?}", fd, name);
+
+        set_cloexec(fd)?;
+        check_listening(fd)?;
+        listeners.push((unsafe { Listener::from_raw_fd(fd)? }, name));
+    }
+
+    Ok(listeners)
+}
+
+fn parse_env(name: &str, value: &str) -> Result<u32, Error> {
+    value.parse::<u32>().map_err(|_| {
+        Error::new(ErrorKind::InvalidInput, format!("{} is not a number: {}", name, value))
+    })
+}
+
+fn set_cloexec(fd: RawFd) -> Result<(), Error> {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFD);
+        if flags < 0 || libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) < 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+/// Errors unless `fd` is a stream socket in the listening state.
+pub fn check_listening(fd: RawFd) -> Result<(), Error> {
+    let mut sock_type: libc::c_int = 0;
+    let mut accepting: libc::c_int = 0;
+    unsafe {
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        if libc::getsockopt(fd,
+                            libc::SOL_SOCKET,
+                            libc::SO_TYPE,
+                            &mut sock_type as *mut _ as *mut libc::c_void,
+                            &mut len) < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        if libc::getsockopt(fd,
+                            libc::SOL_SOCKET,
+                            libc::SO_ACCEPTCONN,
+                            &mut accepting as *mut _ as *mut libc::c_void,
+                            &mut len) < 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+
+    if sock_type != libc::SOCK_STREAM || accepting == 0 {
+        return Err(Error::new(ErrorKind::InvalidInput,
+                              format!("fd {} is not a listening stream socket", fd)));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::net::TcpListener;
+    use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
+    use std::os::unix::net::{UnixListener, UnixStream};
+
+    use libc;
+
+    use super::adopt;
+    use socket::Listener;
+    use test_util::lock_env;
+
+    // Far above any fd the other tests open, as the service manager's would be
+    const FIRST_FD: RawFd = 900;
+
+    /// Sets the `LISTEN_*` variables, for this process unless `pid` is passed.
+    fn set_env(pid: Option<u32>, num_fds: &str, names: Option<&str>) {
+        let pid = pid.unwrap_or(unsafe { libc::getpid() } as u32);
+        env::set_var("LISTEN_PID", pid.to_string());
+        env::set_var("LISTEN_FDS", num_fds);
+        match names {
+            Some(n) => env::set_var("LISTEN_FDNAMES", n),
+            None => env::remove_var("LISTEN_FDNAMES")
+        }
+    }
+
+    fn clear_env() {
+        env::remove_var("LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_FDNAMES");
+    }
+
+    /// Moves `fd` to `FIRST_FD + x`, the way the service manager passes it.
+    fn pass(fd: RawFd, x: RawFd) {
+        unsafe {
+            assert_eq!(libc::dup2(fd, FIRST_FD + x), FIRST_FD + x);
+            libc::close(fd);
+        }
+    }
+
+    #[test]
+    fn adopts_the_passed_listeners_with_their_names() {
+        let _env = lock_env();
+
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let tcp_addr = tcp.local_addr().unwrap();
+        let path = env::temp_dir().join(format!("hydrogen-activation-{}.sock",
+                                                unsafe { libc::getpid() }));
+        let _ = fs::remove_file(&path);
+        let unix = UnixListener::bind(&path).unwrap();
+        pass(tcp.into_raw_fd(), 0);
+        pass(unix.into_raw_fd(), 1);
+
+        set_env(None, "2", Some("web:"));
+        let mut listeners = adopt(FIRST_FD).unwrap();
+        // The variables are left for the caller to remove
+        assert_eq!(env::var("LISTEN_FDS").ok(), Some("2".to_string()));
+        clear_env();
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(listeners.len(), 2);
+        let (unix, unix_name) = listeners.pop().unwrap();
+        let (tcp, tcp_name) = listeners.pop().unwrap();
+        assert_eq!(tcp_name, Some("web".to_string()));
+        assert_eq!(unix_name, None);
+        match tcp {
+            Listener::Tcp(ref l) => assert_eq!(l.local_addr().unwrap(), tcp_addr),
+            ref other => panic!("expected a TCP listener, got {:?}", other)
+        }
+        match unix {
+            Listener::Unix(_) => {}
+            ref other => panic!("expected a Unix listener, got {:?}", other)
+        }
+
+        for fd in [tcp.as_raw_fd(), unix.as_raw_fd()].iter() {
+            let flags = unsafe { libc::fcntl(*fd, libc::F_GETFD) };
+            assert!(flags & libc::FD_CLOEXEC != 0);
+        }
+    }
+
+    #[test]
+    fn ignores_variables_meant_for_another_process() {
+        let _env = lock_env();
+
+        clear_env();
+        assert!(adopt(FIRST_FD).unwrap().is_empty());
+
+        let other = unsafe { libc::getpid() } as u32 + 1;
+        set_env(Some(other), "1", None);
+        let result = adopt(FIRST_FD);
+        clear_env();
+        assert!(result.unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_what_it_can_not_adopt() {
+        let _env = lock_env();
+
+        set_env(None, "many", None);
+        let result = adopt(FIRST_FD);
+        clear_env();
+        assert!(result.is_err());
+
+        // A connected socket is not a listener
+        let (stream, _peer) = UnixStream::pair().unwrap();
+        pass(stream.into_raw_fd(), 0);
+        set_env(None, "1", None);
+        let result = adopt(FIRST_FD);
+        clear_env();
+        assert!(result.is_err());
+        unsafe {
+            libc::close(FIRST_FD);
+        }
+    }
+}
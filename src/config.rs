@@ -6,17 +6,993 @@
 // http://mozilla.org/MPL/2.0/.
 
 
+use std::cmp;
+use std::env;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+use libc;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Deserializer};
+
+use error::ConfigError;
+
+
+// Upper bound on the default pre-allocated slab space, for when the fd limit is unlimited
+const MAX_DEFAULT_PRE_ALLOCATED: usize = 65536;
+
 /// Configuration options for server
+///
+/// Build one with `Config::builder()`, or start from `Config::default()`.
+///
+/// With the `serde` feature, `Config` can also be deserialized from any serde format, such as
+/// TOML or JSON. Missing options take their default. Durations are given in seconds, except
+/// for `epoll_timeout` and `connection.user_timeout`, which are in milliseconds.
+/// Deserializing does not validate, `hydrogen::begin` does.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+#[cfg_attr(feature = "serde", serde(default, deny_unknown_fields))]
+#[non_exhaustive]
 pub struct Config {
-    /// Address to bind to
-    pub addr: String,
-    /// Port to bind to
-    pub port: u16,
+    /// Address and port to bind to
+    pub addr: SocketAddr,
+    /// Whether IPv6 listeners also accept IPv4 connections, as IPv4-mapped addresses.
+    /// Sets `IPV6_V6ONLY` to the opposite before binding, rather than leaving it to the
+    /// host's `net.ipv6.bindv6only` default. Has no effect on IPv4 listeners.
+    pub dual_stack: bool,
+    /// Length of the queue of connections waiting to be accepted, passed to `listen`.
+    /// The kernel caps it at `net.core.somaxconn`.
+    pub backlog: u32,
+    /// Sets `SO_REUSEADDR` on TCP listeners, so a restart can bind while connections from
+    /// the previous run are in `TIME_WAIT`.
+    pub reuse_addr: bool,
+    /// Sets `SO_REUSEPORT` on TCP listeners, so several sockets, in this process or
+    /// another, can bind the same address and have connections spread between them.
+    pub reuse_port: bool,
+    /// Enables `TCP_FASTOPEN` on TCP listeners, with this many pending fast open requests.
+    pub tcp_fastopen: Option<u32>,
+    /// Sets `TCP_DEFER_ACCEPT` on TCP listeners, so connections are only accepted once the
+    /// peer sent data, or this long has passed. Rounded to whole seconds.
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "deserialize_opt_secs"))]
+    pub defer_accept: Option<Duration>,
     /// The number of threads to use for I/O handling.
-    /// The lib itself makes use of 3 threads.
+    /// The lib itself makes use of 3 threads per reactor, 2 with `Accept::EventLoop`.
+    /// Reloadable through `ServerHandle::reload_config`.
     pub max_threads: usize,
     /// The amount of pre-allocated slab space for connections.
     /// This should be, roughly, the maximum amount of concurrent
-    /// connections expected.
-    pub pre_allocated: usize
+    /// connections expected. It is split evenly between reactors.
+    pub pre_allocated: usize,
+    /// How many event loops to run, each with its own epoll instance and connections, and its
+    /// own socket for every TCP listener, bound with `SO_REUSEPORT` so the kernel spreads
+    /// connections between them. They share the handler, the I/O thread pool and
+    /// `ServerHandle`. Defaults to 1.
+    ///
+    /// `SO_REUSEPORT` is set whenever this is above 1, whatever `reuse_port` says, and
+    /// `Handler::on_socket_created` and `on_server_created` are called for every reactor's
+    /// socket. Unix listeners, and listeners that were inherited or socket activated, are
+    /// only accepted from by the first reactor. `ServerHandle::send_listeners` refuses to hand
+    /// off the listeners of more than one reactor.
+    pub reactors: usize,
+    /// How each reactor is notified of readiness. Defaults to `Backend::Epoll`.
+    pub backend: Backend,
+    /// The most events taken from one `epoll_wait` call. Defaults to 100.
+    pub max_events: usize,
+    /// How long the event loop blocks in `epoll_wait` when no I/O is reported. It is woken
+    /// early for new connections, stop requests and connections whose I/O failed, so this
+    /// only bounds how late a connection that errored elsewhere, such as in a
+    /// `HydrogenSocket::send` from another thread, is removed. Defaults to 1 second. Rounded
+    /// to whole milliseconds.
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "deserialize_millis"))]
+    pub epoll_timeout: Duration,
+    /// Whether connections are registered edge-triggered or level-triggered.
+    pub trigger: Trigger,
+    /// Registers listening sockets with `EPOLLEXCLUSIVE`, so only one of several waiters on
+    /// a shared listener is woken per connection, such as a process and its successor after
+    /// `ServerHandle::send_listeners`. Connections can not use it, since they are re-armed
+    /// with `EPOLL_CTL_MOD`.
+    pub epoll_exclusive: bool,
+    /// Which thread accepts connections. Defaults to `Accept::Thread`.
+    pub accept: Accept,
+    /// With `Accept::EventLoop`, the most connections accepted from one listener each time
+    /// around the event loop, so a connect storm does not starve open connections. The rest
+    /// are accepted on the next time around. Defaults to 64.
+    pub accept_batch: usize,
+    /// Watch for SIGTERM, SIGINT, SIGHUP, SIGUSR1 and SIGUSR2 through a signalfd and report
+    /// them to `Handler::on_signal`.
+    /// The signals are blocked on the thread calling `hydrogen::begin`, and on every thread
+    /// it spawns, so `begin` should be called before any other thread is started. They stay
+    /// blocked on the calling thread after the server stops. Each signal is only delivered
+    /// to one server, so at most one server per process should set this.
+    pub handle_signals: bool,
+    /// How long to drain for when SIGTERM or SIGINT stops the server.
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "deserialize_secs"))]
+    pub drain_timeout: Duration,
+    /// Adopt the listening sockets passed by a service manager through `LISTEN_FDS`,
+    /// `LISTEN_PID` and `LISTEN_FDNAMES` instead of binding `addr` and `extra_listeners`.
+    /// Falls back to binding if the process was not socket activated. Listeners get their
+    /// `ListenerId` in `LISTEN_FDS` order, and `ServerHandle::listener_name` maps one back to
+    /// its name. The variables are left in the environment.
+    pub socket_activation: bool,
+    /// More addresses to listen on, sharing the server's event loop and thread pool.
+    /// `addr` is `ListenerId(0)`, these follow as `ListenerId(1)` onwards.
+    pub extra_listeners: Vec<Listen>,
+    /// Socket options set on every accepted connection.
+    pub connection: ConnectionOptions
+}
+
+/// Socket options hydrogen sets on each accepted connection, before
+/// `Handler::on_new_connection` is called. Options left as `None` keep the kernel's default.
+///
+/// Only the buffer sizes and `linger` apply to Unix domain connections, the rest are skipped
+/// for them. Failures are reported to `Handler::on_connection_options_failed`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+#[cfg_attr(feature = "serde", serde(default, deny_unknown_fields))]
+#[non_exhaustive]
+pub struct ConnectionOptions {
+    /// `TCP_NODELAY`, sending small writes without waiting to coalesce them
+    pub nodelay: Option<bool>,
+    /// `SO_KEEPALIVE`, probing idle connections to notice dead peers
+    pub keepalive: Option<bool>,
+    /// `TCP_KEEPIDLE`, how long a connection is idle before probes are sent.
+    /// Rounded to whole seconds.
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "deserialize_opt_secs"))]
+    pub keepalive_idle: Option<Duration>,
+    /// `TCP_KEEPINTVL`, the time between probes. Rounded to whole seconds.
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "deserialize_opt_secs"))]
+    pub keepalive_interval: Option<Duration>,
+    /// `TCP_KEEPCNT`, how many unanswered probes drop the connection
+    pub keepalive_count: Option<u32>,
+    /// `SO_RCVBUF`, in bytes
+    pub recv_buffer_size: Option<u32>,
+    /// `SO_SNDBUF`, in bytes
+    pub send_buffer_size: Option<u32>,
+    /// `TCP_USER_TIMEOUT`, how long sent data may stay unacknowledged before the connection
+    /// is dropped. Rounded to whole milliseconds.
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "deserialize_opt_millis"))]
+    pub user_timeout: Option<Duration>,
+    /// `IP_TOS` for IPv4 connections, `IPV6_TCLASS` for IPv6 ones
+    pub tos: Option<u8>,
+    /// `TCP_CONGESTION`, the name of the congestion control algorithm, such as `"bbr"`
+    pub congestion_control: Option<String>,
+    /// `SO_LINGER`, how long closing blocks to send what is left. Rounded to whole seconds,
+    /// so a zero duration resets the connection on close.
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "deserialize_opt_secs"))]
+    pub linger: Option<Duration>
+}
+
+/// How connections are registered with epoll, see `Config::trigger`.
+///
+/// Either way, each connection is registered with `EPOLLONESHOT`, so only one I/O thread
+/// handles it at a time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+#[non_exhaustive]
+pub enum Trigger {
+    /// `EPOLLET`. Events are only reported when new data arrives, so `Stream::recv` has to
+    /// read until `ErrorKind::WouldBlock`.
+    Edge,
+    /// Events are reported again on re-arm for as long as data is waiting, so a `recv` that
+    /// stops early is called again. Costs an extra wakeup for every read.
+    Level
+}
+
+/// How readiness is waited on, see `Config::backend`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+#[non_exhaustive]
+pub enum Backend {
+    /// `epoll(7)`
+    Epoll,
+    /// `poll(2)`, for sandboxes where epoll is missing or unreliable. It rebuilds its fd list
+    /// on every wait and is woken on every re-arm, so it costs more per connection. It is
+    /// always level-triggered, `trigger` and `epoll_exclusive` have no effect.
+    Poll,
+    /// io_uring, with the `io-uring` cargo feature. Falls back to epoll when the kernel is
+    /// older than 5.11 or io_uring is disabled. Listeners are accepted on with a multishot
+    /// accept from Linux 5.19 on. Connections whose `Stream::completion_io` is set are received
+    /// on into provided buffers, with a multishot recv from Linux 6.0 on, and sent on by the
+    /// ring, their `Stream` only decoding and encoding. Other connections, and every
+    /// connection on kernels older than 5.19, are polled and read and written through their
+    /// `Stream`. It is always level-triggered, `trigger` and `epoll_exclusive` have no effect.
+    #[cfg(feature = "io-uring")]
+    IoUring
+}
+
+/// Where connections are accepted, see `Config::accept`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+#[non_exhaustive]
+pub enum Accept {
+    /// On a thread of its own, which drains each listener's backlog and hands the
+    /// connections to the event loop.
+    Thread,
+    /// On the event loop, which watches the listeners in its own epoll instance and accepts
+    /// up to `Config::accept_batch` connections from each at a time. Saves a thread, a lock
+    /// and a wakeup per connection, but `Handler::on_new_connection` is called on the event
+    /// loop, so a slow one holds up I/O on every connection.
+    EventLoop
+}
+
+/// An address to listen on, see `Config::extra_listeners`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "type", rename_all = "snake_case"))]
+#[non_exhaustive]
+pub enum Listen {
+    /// TCP on `addr`
+    Tcp { addr: SocketAddr },
+    /// Unix domain stream socket at `path`.
+    ///
+    /// A socket file left behind by a previous run is removed before binding, and the file
+    /// is removed again once the server stops, unless the listener was handed to another
+    /// process with `ServerHandle::send_listeners`. If `mode` is set, the file's permissions
+    /// are changed to it after binding.
+    Unix { path: PathBuf, mode: Option<u32> },
+    /// Unix domain stream socket named `name` in the abstract namespace
+    AbstractUnix { name: String }
+}
+
+impl Config {
+    /// Returns a builder starting from `Config::default()`.
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder { cfg: Config::default(), err: None }
+    }
+
+    /// Returns the default `Config`, overridden by whichever of the following environment
+    /// variables are set:
+    ///
+    /// * `{prefix}ADDR`, as an IP address, or an address and port such as `[::]:1337`
+    /// * `{prefix}PORT`, `{prefix}BACKLOG`, `{prefix}MAX_THREADS`, `{prefix}PRE_ALLOCATED`,
+    ///   `{prefix}REACTORS`, `{prefix}MAX_EVENTS`, `{prefix}ACCEPT_BATCH`
+    /// * `{prefix}DUAL_STACK`, `{prefix}REUSE_ADDR`, `{prefix}REUSE_PORT`,
+    ///   `{prefix}HANDLE_SIGNALS`, `{prefix}SOCKET_ACTIVATION`, `{prefix}EPOLL_EXCLUSIVE`, as
+    ///   `true`/`false` or `1`/`0`
+    /// * `{prefix}EPOLL_TIMEOUT`, in milliseconds
+    /// * `{prefix}TRIGGER`, as `edge` or `level`
+    /// * `{prefix}ACCEPT`, as `thread` or `event_loop`
+    /// * `{prefix}BACKEND`, as `epoll` or `poll`, or `io_uring` with the `io-uring` feature
+    /// * `{prefix}TCP_FASTOPEN`, as a queue length, `0` turning it off
+    /// * `{prefix}DRAIN_TIMEOUT` and `{prefix}DEFER_ACCEPT`, in seconds, a `DEFER_ACCEPT` of
+    ///   `0` turning it off
+    /// * The options of `Config::connection`, named after their fields, such as
+    ///   `{prefix}NODELAY` or `{prefix}KEEPALIVE_IDLE`. Durations are in seconds, except
+    ///   `{prefix}USER_TIMEOUT`, in milliseconds.
+    ///
+    /// e.g. `Config::from_env("HYDROGEN_")` reads `HYDROGEN_PORT`. Use `ConfigBuilder::env`
+    /// to start from something other than the defaults.
+    pub fn from_env(prefix: &str) -> Result<Config, ConfigError> {
+        Config::builder().env(prefix)?.build()
+    }
+
+    /// Checks the options for values the server can not start with.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.max_threads == 0 {
+            return Err(ConfigError::MaxThreads);
+        }
+        if self.reactors == 0 {
+            return Err(ConfigError::Reactors);
+        }
+        if self.max_events == 0 || self.max_events > i32::MAX as usize {
+            return Err(ConfigError::MaxEvents);
+        }
+        if self.accept_batch == 0 {
+            return Err(ConfigError::AcceptBatch);
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Config {
+    /// Binds `0.0.0.0` on an ephemeral port, with one I/O thread per CPU and slab space for as
+    /// many connections as the process may open fds.
+    fn default() -> Config {
+        Config {
+            addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+            dual_stack: true,
+            backlog: libc::SOMAXCONN as u32,
+            reuse_addr: true,
+            reuse_port: false,
+            tcp_fastopen: None,
+            defer_accept: None,
+            max_threads: default_max_threads(),
+            pre_allocated: default_pre_allocated(),
+            reactors: 1,
+            backend: Backend::Epoll,
+            max_events: 100,
+            epoll_timeout: Duration::from_millis(1000),
+            trigger: Trigger::Edge,
+            epoll_exclusive: false,
+            accept: Accept::Thread,
+            accept_batch: 64,
+            handle_signals: false,
+            drain_timeout: Duration::from_secs(30),
+            socket_activation: false,
+            extra_listeners: Vec::new(),
+            connection: ConnectionOptions::default()
+        }
+    }
+}
+
+/// Builds a validated `Config`, returned from `Config::builder`.
+#[derive(Debug, Clone)]
+pub struct ConfigBuilder {
+    cfg: Config,
+    /// First failure to resolve an address, returned from `build`
+    err: Option<ConfigError>
+}
+
+impl ConfigBuilder {
+    /// Sets `Config::addr` to the first address `addr` resolves to, such as
+    /// `"127.0.0.1:1337"`, `"[::]:1337"` or `("localhost", 1337)`.
+    pub fn addr<A: ToSocketAddrs>(mut self, addr: A) -> ConfigBuilder {
+        match resolve(addr) {
+            Ok(a) => self.cfg.addr = a,
+            Err(err) => self.fail(err)
+        }
+        self
+    }
+
+    /// Sets the port of `Config::addr`, keeping its IP address.
+    pub fn port(mut self, port: u16) -> ConfigBuilder {
+        self.cfg.addr.set_port(port);
+        self
+    }
+
+    /// Sets `Config::dual_stack`.
+    pub fn dual_stack(mut self, dual_stack: bool) -> ConfigBuilder {
+        self.cfg.dual_stack = dual_stack;
+        self
+    }
+
+    /// Sets `Config::backlog`.
+    pub fn backlog(mut self, backlog: u32) -> ConfigBuilder {
+        self.cfg.backlog = backlog;
+        self
+    }
+
+    /// Sets `Config::reuse_addr`.
+    pub fn reuse_addr(mut self, reuse_addr: bool) -> ConfigBuilder {
+        self.cfg.reuse_addr = reuse_addr;
+        self
+    }
+
+    /// Sets `Config::reuse_port`.
+    pub fn reuse_port(mut self, reuse_port: bool) -> ConfigBuilder {
+        self.cfg.reuse_port = reuse_port;
+        self
+    }
+
+    /// Sets `Config::tcp_fastopen`.
+    pub fn tcp_fastopen(mut self, queue_len: Option<u32>) -> ConfigBuilder {
+        self.cfg.tcp_fastopen = queue_len;
+        self
+    }
+
+    /// Sets `Config::defer_accept`.
+    pub fn defer_accept(mut self, timeout: Option<Duration>) -> ConfigBuilder {
+        self.cfg.defer_accept = timeout;
+        self
+    }
+
+    /// Sets `Config::max_threads`.
+    pub fn max_threads(mut self, max_threads: usize) -> ConfigBuilder {
+        self.cfg.max_threads = max_threads;
+        self
+    }
+
+    /// Sets `Config::pre_allocated`.
+    pub fn pre_allocated(mut self, pre_allocated: usize) -> ConfigBuilder {
+        self.cfg.pre_allocated = pre_allocated;
+        self
+    }
+
+    /// Sets `Config::reactors`.
+    pub fn reactors(mut self, reactors: usize) -> ConfigBuilder {
+        self.cfg.reactors = reactors;
+        self
+    }
+
+    /// Sets `Config::backend`.
+    pub fn backend(mut self, backend: Backend) -> ConfigBuilder {
+        self.cfg.backend = backend;
+        self
+    }
+
+    /// Sets `Config::max_events`.
+    pub fn max_events(mut self, max_events: usize) -> ConfigBuilder {
+        self.cfg.max_events = max_events;
+        self
+    }
+
+    /// Sets `Config::epoll_timeout`.
+    pub fn epoll_timeout(mut self, timeout: Duration) -> ConfigBuilder {
+        self.cfg.epoll_timeout = timeout;
+        self
+    }
+
+    /// Sets `Config::trigger`.
+    pub fn trigger(mut self, trigger: Trigger) -> ConfigBuilder {
+        self.cfg.trigger = trigger;
+        self
+    }
+
+    /// Sets `Config::epoll_exclusive`.
+    pub fn epoll_exclusive(mut self, epoll_exclusive: bool) -> ConfigBuilder {
+        self.cfg.epoll_exclusive = epoll_exclusive;
+        self
+    }
+
+    /// Sets `Config::accept`.
+    pub fn accept(mut self, accept: Accept) -> ConfigBuilder {
+        self.cfg.accept = accept;
+        self
+    }
+
+    /// Sets `Config::accept_batch`.
+    pub fn accept_batch(mut self, accept_batch: usize) -> ConfigBuilder {
+        self.cfg.accept_batch = accept_batch;
+        self
+    }
+
+    /// Sets `Config::handle_signals`.
+    pub fn handle_signals(mut self, handle_signals: bool) -> ConfigBuilder {
+        self.cfg.handle_signals = handle_signals;
+        self
+    }
+
+    /// Sets `Config::drain_timeout`.
+    pub fn drain_timeout(mut self, drain_timeout: Duration) -> ConfigBuilder {
+        self.cfg.drain_timeout = drain_timeout;
+        self
+    }
+
+    /// Sets `Config::socket_activation`.
+    pub fn socket_activation(mut self, socket_activation: bool) -> ConfigBuilder {
+        self.cfg.socket_activation = socket_activation;
+        self
+    }
+
+    /// Adds an entry to `Config::extra_listeners`.
+    pub fn listen(mut self, listen: Listen) -> ConfigBuilder {
+        self.cfg.extra_listeners.push(listen);
+        self
+    }
+
+    /// Adds a TCP listener on the first address `addr` resolves to, to
+    /// `Config::extra_listeners`.
+    pub fn listen_tcp<A: ToSocketAddrs>(mut self, addr: A) -> ConfigBuilder {
+        match resolve(addr) {
+            Ok(a) => self.cfg.extra_listeners.push(Listen::Tcp { addr: a }),
+            Err(err) => self.fail(err)
+        }
+        self
+    }
+
+    /// Adds a Unix domain socket listener at `path` to `Config::extra_listeners`.
+    pub fn listen_unix<P: Into<PathBuf>>(mut self, path: P, mode: Option<u32>) -> ConfigBuilder {
+        self.cfg.extra_listeners.push(Listen::Unix { path: path.into(), mode: mode });
+        self
+    }
+
+    /// Sets `Config::connection`.
+    pub fn connection(mut self, connection: ConnectionOptions) -> ConfigBuilder {
+        self.cfg.connection = connection;
+        self
+    }
+
+    /// Overrides the options set so far with the environment variables described in
+    /// `Config::from_env`.
+    pub fn env(mut self, prefix: &str) -> Result<ConfigBuilder, ConfigError> {
+        if let Some(addr) = read_env(prefix, "ADDR") {
+            if let Ok(ip) = addr.trim().parse::<IpAddr>() {
+                self.cfg.addr.set_ip(ip);
+            } else {
+                match resolve(addr.trim()) {
+                    Ok(a) => self.cfg.addr = a,
+                    Err(_) => return Err(ConfigError::Env(format!("{}ADDR", prefix), addr))
+                }
+            }
+        }
+        if let Some(port) = read_env(prefix, "PORT") {
+            let port = parse_env(prefix, "PORT", port)?;
+            self.cfg.addr.set_port(port);
+        }
+        if let Some(dual_stack) = read_env(prefix, "DUAL_STACK") {
+            self.cfg.dual_stack = parse_bool_env(prefix, "DUAL_STACK", dual_stack)?;
+        }
+        if let Some(backlog) = read_env(prefix, "BACKLOG") {
+            self.cfg.backlog = parse_env(prefix, "BACKLOG", backlog)?;
+        }
+        if let Some(reuse_addr) = read_env(prefix, "REUSE_ADDR") {
+            self.cfg.reuse_addr = parse_bool_env(prefix, "REUSE_ADDR", reuse_addr)?;
+        }
+        if let Some(reuse_port) = read_env(prefix, "REUSE_PORT") {
+            self.cfg.reuse_port = parse_bool_env(prefix, "REUSE_PORT", reuse_port)?;
+        }
+        if let Some(queue_len) = read_env(prefix, "TCP_FASTOPEN") {
+            self.cfg.tcp_fastopen = match parse_env(prefix, "TCP_FASTOPEN", queue_len)? {
+                0 => None,
+                n => Some(n)
+            };
+        }
+        if let Some(secs) = read_env(prefix, "DEFER_ACCEPT") {
+            self.cfg.defer_accept = match parse_env(prefix, "DEFER_ACCEPT", secs)? {
+                0 => None,
+                n => Some(Duration::from_secs(n))
+            };
+        }
+        if let Some(max_threads) = read_env(prefix, "MAX_THREADS") {
+            self.cfg.max_threads = parse_env(prefix, "MAX_THREADS", max_threads)?;
+        }
+        if let Some(pre_allocated) = read_env(prefix, "PRE_ALLOCATED") {
+            self.cfg.pre_allocated = parse_env(prefix, "PRE_ALLOCATED", pre_allocated)?;
+        }
+        if let Some(reactors) = read_env(prefix, "REACTORS") {
+            self.cfg.reactors = parse_env(prefix, "REACTORS", reactors)?;
+        }
+        if let Some(backend) = read_env(prefix, "BACKEND") {
+            self.cfg.backend = match backend.trim() {
+                "epoll" => Backend::Epoll,
+                "poll" => Backend::Poll,
+                #[cfg(feature = "io-uring")]
+                "io_uring" => Backend::IoUring,
+                _ => return Err(ConfigError::Env(format!("{}BACKEND", prefix), backend))
+            };
+        }
+        if let Some(max_events) = read_env(prefix, "MAX_EVENTS") {
+            self.cfg.max_events = parse_env(prefix, "MAX_EVENTS", max_events)?;
+        }
+        if let Some(millis) = read_env(prefix, "EPOLL_TIMEOUT") {
+            let millis = parse_env(prefix, "EPOLL_TIMEOUT", millis)?;
+            self.cfg.epoll_timeout = Duration::from_millis(millis);
+        }
+        if let Some(trigger) = read_env(prefix, "TRIGGER") {
+            self.cfg.trigger = match trigger.trim() {
+                "edge" => Trigger::Edge,
+                "level" => Trigger::Level,
+                _ => return Err(ConfigError::Env(format!("{}TRIGGER", prefix), trigger))
+            };
+        }
+        if let Some(exclusive) = read_env(prefix, "EPOLL_EXCLUSIVE") {
+            self.cfg.epoll_exclusive = parse_bool_env(prefix, "EPOLL_EXCLUSIVE", exclusive)?;
+        }
+        if let Some(accept) = read_env(prefix, "ACCEPT") {
+            self.cfg.accept = match accept.trim() {
+                "thread" => Accept::Thread,
+                "event_loop" => Accept::EventLoop,
+                _ => return Err(ConfigError::Env(format!("{}ACCEPT", prefix), accept))
+            };
+        }
+        if let Some(accept_batch) = read_env(prefix, "ACCEPT_BATCH") {
+            self.cfg.accept_batch = parse_env(prefix, "ACCEPT_BATCH", accept_batch)?;
+        }
+        if let Some(handle_signals) = read_env(prefix, "HANDLE_SIGNALS") {
+            self.cfg.handle_signals = parse_bool_env(prefix, "HANDLE_SIGNALS", handle_signals)?;
+        }
+        if let Some(secs) = read_env(prefix, "DRAIN_TIMEOUT") {
+            self.cfg.drain_timeout = Duration::from_secs(parse_env(prefix, "DRAIN_TIMEOUT", secs)?);
+        }
+        if let Some(activation) = read_env(prefix, "SOCKET_ACTIVATION") {
+            self.cfg.socket_activation = parse_bool_env(prefix, "SOCKET_ACTIVATION", activation)?;
+        }
+
+        self.cfg.connection.env(prefix)?;
+
+        Ok(self)
+    }
+
+    /// Validates and returns the `Config`.
+    pub fn build(self) -> Result<Config, ConfigError> {
+        if let Some(err) = self.err {
+            return Err(err);
+        }
+
+        self.cfg.validate()?;
+        Ok(self.cfg)
+    }
+
+    /// Keeps the first error, later setters should not hide what went wrong first.
+    fn fail(&mut self, err: ConfigError) {
+        if self.err.is_none() {
+            self.err = Some(err);
+        }
+    }
+}
+
+impl ConnectionOptions {
+    /// Sets `nodelay`.
+    pub fn nodelay(mut self, nodelay: bool) -> ConnectionOptions {
+        self.nodelay = Some(nodelay);
+        self
+    }
+
+    /// Sets `keepalive`.
+    pub fn keepalive(mut self, keepalive: bool) -> ConnectionOptions {
+        self.keepalive = Some(keepalive);
+        self
+    }
+
+    /// Sets `keepalive_idle`.
+    pub fn keepalive_idle(mut self, idle: Duration) -> ConnectionOptions {
+        self.keepalive_idle = Some(idle);
+        self
+    }
+
+    /// Sets `keepalive_interval`.
+    pub fn keepalive_interval(mut self, interval: Duration) -> ConnectionOptions {
+        self.keepalive_interval = Some(interval);
+        self
+    }
+
+    /// Sets `keepalive_count`.
+    pub fn keepalive_count(mut self, count: u32) -> ConnectionOptions {
+        self.keepalive_count = Some(count);
+        self
+    }
+
+    /// Sets `recv_buffer_size`.
+    pub fn recv_buffer_size(mut self, size: u32) -> ConnectionOptions {
+        self.recv_buffer_size = Some(size);
+        self
+    }
+
+    /// Sets `send_buffer_size`.
+    pub fn send_buffer_size(mut self, size: u32) -> ConnectionOptions {
+        self.send_buffer_size = Some(size);
+        self
+    }
+
+    /// Sets `user_timeout`.
+    pub fn user_timeout(mut self, timeout: Duration) -> ConnectionOptions {
+        self.user_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets `tos`.
+    pub fn tos(mut self, tos: u8) -> ConnectionOptions {
+        self.tos = Some(tos);
+        self
+    }
+
+    /// Sets `congestion_control`.
+    pub fn congestion_control<S: Into<String>>(mut self, name: S) -> ConnectionOptions {
+        self.congestion_control = Some(name.into());
+        self
+    }
+
+    /// Sets `linger`.
+    pub fn linger(mut self, linger: Duration) -> ConnectionOptions {
+        self.linger = Some(linger);
+        self
+    }
+
+    /// Overrides options with the environment variables described in `Config::from_env`.
+    fn env(&mut self, prefix: &str) -> Result<(), ConfigError> {
+        if let Some(nodelay) = read_env(prefix, "NODELAY") {
+            self.nodelay = Some(parse_bool_env(prefix, "NODELAY", nodelay)?);
+        }
+        if let Some(keepalive) = read_env(prefix, "KEEPALIVE") {
+            self.keepalive = Some(parse_bool_env(prefix, "KEEPALIVE", keepalive)?);
+        }
+        if let Some(secs) = read_env(prefix, "KEEPALIVE_IDLE") {
+            let secs = parse_env(prefix, "KEEPALIVE_IDLE", secs)?;
+            self.keepalive_idle = Some(Duration::from_secs(secs));
+        }
+        if let Some(secs) = read_env(prefix, "KEEPALIVE_INTERVAL") {
+            let secs = parse_env(prefix, "KEEPALIVE_INTERVAL", secs)?;
+            self.keepalive_interval = Some(Duration::from_secs(secs));
+        }
+        if let Some(count) = read_env(prefix, "KEEPALIVE_COUNT") {
+            self.keepalive_count = Some(parse_env(prefix, "KEEPALIVE_COUNT", count)?);
+        }
+        if let Some(size) = read_env(prefix, "RECV_BUFFER_SIZE") {
+            self.recv_buffer_size = Some(parse_env(prefix, "RECV_BUFFER_SIZE", size)?);
+        }
+        if let Some(size) = read_env(prefix, "SEND_BUFFER_SIZE") {
+            self.send_buffer_size = Some(parse_env(prefix, "SEND_BUFFER_SIZE", size)?);
+        }
+        if let Some(millis) = read_env(prefix, "USER_TIMEOUT") {
+            let millis = parse_env(prefix, "USER_TIMEOUT", millis)?;
+            self.user_timeout = Some(Duration::from_millis(millis));
+        }
+        if let Some(tos) = read_env(prefix, "TOS") {
+            self.tos = Some(parse_env(prefix, "TOS", tos)?);
+        }
+        if let Some(name) = read_env(prefix, "CONGESTION_CONTROL") {
+            self.congestion_control = Some(name.trim().to_string());
+        }
+        if let Some(secs) = read_env(prefix, "LINGER") {
+            self.linger = Some(Duration::from_secs(parse_env(prefix, "LINGER", secs)?));
+        }
+
+        Ok(())
+    }
+}
+
+fn resolve<A: ToSocketAddrs>(addr: A) -> Result<SocketAddr, ConfigError> {
+    match addr.to_socket_addrs() {
+        Ok(mut addrs) => match addrs.next() {
+            Some(a) => Ok(a),
+            None => Err(ConfigError::Addr("Resolved to no addresses".to_string()))
+        },
+        Err(err) => Err(ConfigError::Addr(err.to_string()))
+    }
+}
+
+fn read_env(prefix: &str, name: &str) -> Option<String> {
+    env::var(format!("{}{}", prefix, name)).ok()
+}
+
+fn parse_env<T: FromStr>(prefix: &str, name: &str, value: String) -> Result<T, ConfigError> {
+    match value.trim().parse::<T>() {
+        Ok(v) => Ok(v),
+        Err(_) => Err(ConfigError::Env(format!("{}{}", prefix, name), value))
+    }
+}
+
+fn parse_bool_env(prefix: &str, name: &str, value: String) -> Result<bool, ConfigError> {
+    match value.trim() {
+        "true" | "1" => Ok(true),
+        "false" | "0" => Ok(false),
+        _ => Err(ConfigError::Env(format!("{}{}", prefix, name), value))
+    }
+}
+
+#[cfg(feature = "serde")]
+fn deserialize_secs<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    u64::deserialize(deserializer).map(Duration::from_secs)
+}
+
+#[cfg(feature = "serde")]
+fn deserialize_millis<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    u64::deserialize(deserializer).map(Duration::from_millis)
+}
+
+#[cfg(feature = "serde")]
+fn deserialize_opt_secs<'de, D: Deserializer<'de>>(deserializer: D)
+    -> Result<Option<Duration>, D::Error>
+{
+    Option::<u64>::deserialize(deserializer).map(|secs| secs.map(Duration::from_secs))
+}
+
+#[cfg(feature = "serde")]
+fn deserialize_opt_millis<'de, D: Deserializer<'de>>(deserializer: D)
+    -> Result<Option<Duration>, D::Error>
+{
+    Option::<u64>::deserialize(deserializer).map(|millis| millis.map(Duration::from_millis))
+}
+
+fn default_max_threads() -> usize {
+    thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+}
+
+/// The soft `RLIMIT_NOFILE`, capped at `MAX_DEFAULT_PRE_ALLOCATED`.
+fn default_pre_allocated() -> usize {
+    let mut limit: libc::rlimit = unsafe { mem::zeroed() };
+    let result = unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) };
+    if result < 0 || limit.rlim_cur == libc::RLIM_INFINITY {
+        return MAX_DEFAULT_PRE_ALLOCATED;
+    }
+
+    cmp::min(limit.rlim_cur as usize, MAX_DEFAULT_PRE_ALLOCATED)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::net::SocketAddr;
+    #[cfg(feature = "serde")]
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    #[cfg(feature = "serde")]
+    use serde_json;
+    #[cfg(feature = "serde")]
+    use toml;
+
+    use super::{Accept, Config, Trigger};
+    #[cfg(feature = "serde")]
+    use super::Listen;
+    use error::ConfigError;
+    use test_util::lock_env;
+
+    #[test]
+    fn default_is_valid() {
+        assert_eq!(Config::default().validate(), Ok(()));
+    }
+
+    #[test]
+    fn validate_rejects_zeroes() {
+        let cfg = Config { max_threads: 0, ..Config::default() };
+        assert_eq!(cfg.validate(), Err(ConfigError::MaxThreads));
+
+        let cfg = Config { reactors: 0, ..Config::default() };
+        assert_eq!(cfg.validate(), Err(ConfigError::Reactors));
+
+        let cfg = Config { max_events: 0, ..Config::default() };
+        assert_eq!(cfg.validate(), Err(ConfigError::MaxEvents));
+
+        let cfg = Config { accept_batch: 0, ..Config::default() };
+        assert_eq!(cfg.validate(), Err(ConfigError::AcceptBatch));
+    }
+
+    #[test]
+    fn validate_bounds_max_events_by_epoll_wait() {
+        let mut cfg = Config { max_events: i32::MAX as usize, ..Config::default() };
+        assert_eq!(cfg.validate(), Ok(()));
+
+        cfg.max_events += 1;
+        assert_eq!(cfg.validate(), Err(ConfigError::MaxEvents));
+    }
+
+    #[test]
+    fn builder_validates() {
+        assert_eq!(Config::builder().max_threads(0).build().err(), Some(ConfigError::MaxThreads));
+    }
+
+    #[test]
+    fn builder_keeps_the_first_addr_error() {
+        let result = Config::builder()
+            .addr("not an address")
+            .addr("also not")
+            .max_threads(0)
+            .build();
+        match result {
+            Err(ConfigError::Addr(_)) => {}
+            other => panic!("expected an Addr error, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn env_overrides_the_builder() {
+        let _env = lock_env();
+        // Each test has a prefix of its own, as the variables are left set
+        env::set_var("HYDROGEN_TEST_ENV_ADDR", "127.0.0.1");
+        env::set_var("HYDROGEN_TEST_ENV_PORT", " 1337 ");
+        env::set_var("HYDROGEN_TEST_ENV_MAX_THREADS", "3");
+        env::set_var("HYDROGEN_TEST_ENV_REUSE_PORT", "1");
+        env::set_var("HYDROGEN_TEST_ENV_DUAL_STACK", "false");
+        env::set_var("HYDROGEN_TEST_ENV_EPOLL_TIMEOUT", "250");
+        env::set_var("HYDROGEN_TEST_ENV_DRAIN_TIMEOUT", "5");
+        env::set_var("HYDROGEN_TEST_ENV_DEFER_ACCEPT", "0");
+        env::set_var("HYDROGEN_TEST_ENV_TRIGGER", "level");
+        env::set_var("HYDROGEN_TEST_ENV_ACCEPT", "event_loop");
+        env::set_var("HYDROGEN_TEST_ENV_KEEPALIVE_IDLE", "60");
+        env::set_var("HYDROGEN_TEST_ENV_USER_TIMEOUT", "1500");
+
+        let cfg = Config::builder()
+            .max_threads(1)
+            .backlog(16)
+            .defer_accept(Some(Duration::from_secs(1)))
+            .env("HYDROGEN_TEST_ENV_")
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(cfg.addr, "127.0.0.1:1337".parse::<SocketAddr>().unwrap());
+        assert_eq!(cfg.max_threads, 3);
+        assert_eq!(cfg.backlog, 16);
+        assert!(cfg.reuse_port);
+        assert!(!cfg.dual_stack);
+        assert_eq!(cfg.epoll_timeout, Duration::from_millis(250));
+        assert_eq!(cfg.drain_timeout, Duration::from_secs(5));
+        assert_eq!(cfg.defer_accept, None);
+        assert_eq!(cfg.trigger, Trigger::Level);
+        assert_eq!(cfg.accept, Accept::EventLoop);
+        assert_eq!(cfg.connection.keepalive_idle, Some(Duration::from_secs(60)));
+        assert_eq!(cfg.connection.user_timeout, Some(Duration::from_millis(1500)));
+    }
+
+    #[test]
+    fn env_keeps_the_port_when_only_the_ip_is_set() {
+        let _env = lock_env();
+        env::set_var("HYDROGEN_TEST_IP_ADDR", "::1");
+
+        let cfg = Config::builder().port(8080).env("HYDROGEN_TEST_IP_").unwrap().build().unwrap();
+        assert_eq!(cfg.addr, "[::1]:8080".parse::<SocketAddr>().unwrap());
+    }
+
+    #[test]
+    fn env_rejects_invalid_values() {
+        let _env = lock_env();
+        let invalid = [
+            ("PORT", "http"),
+            ("MAX_THREADS", "-1"),
+            ("REUSE_ADDR", "yes"),
+            ("TRIGGER", "both"),
+            ("ACCEPT", "inline"),
+            ("BACKEND", "kqueue"),
+            ("NODELAY", "on")
+        ];
+        for &(name, value) in invalid.iter() {
+            let prefix = format!("HYDROGEN_TEST_INVALID_{}_", name);
+            env::set_var(format!("{}{}", prefix, name), value);
+
+            let err = Config::builder().env(&prefix).err();
+            let expected = ConfigError::Env(format!("{}{}", prefix, name), value.to_string());
+            assert_eq!(err, Some(expected));
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn deserializes_the_same_config_from_toml_and_json() {
+        let from_toml: Config = toml::from_str(r#"
+            addr = "127.0.0.1:1337"
+            max_threads = 2
+            epoll_timeout = 250
+            drain_timeout = 5
+            defer_accept = 3
+            trigger = "level"
+            accept = "event_loop"
+
+            [[extra_listeners]]
+            type = "tcp"
+            addr = "[::1]:8080"
+
+            [[extra_listeners]]
+            type = "unix"
+            path = "/run/hydrogen.sock"
+            mode = 0o660
+
+            [[extra_listeners]]
+            type = "abstract_unix"
+            name = "hydrogen"
+
+            [connection]
+            keepalive_idle = 60
+            user_timeout = 1500
+        "#).unwrap();
+        let from_json: Config = serde_json::from_str(r#"{
+            "addr": "127.0.0.1:1337",
+            "max_threads": 2,
+            "epoll_timeout": 250,
+            "drain_timeout": 5,
+            "defer_accept": 3,
+            "trigger": "level",
+            "accept": "event_loop",
+            "extra_listeners": [
+                { "type": "tcp", "addr": "[::1]:8080" },
+                { "type": "unix", "path": "/run/hydrogen.sock", "mode": 432 },
+                { "type": "abstract_unix", "name": "hydrogen" }
+            ],
+            "connection": { "keepalive_idle": 60, "user_timeout": 1500 }
+        }"#).unwrap();
+
+        for cfg in [from_toml, from_json].iter() {
+            assert_eq!(cfg.addr, "127.0.0.1:1337".parse::<SocketAddr>().unwrap());
+            assert_eq!(cfg.max_threads, 2);
+            assert_eq!(cfg.epoll_timeout, Duration::from_millis(250));
+            assert_eq!(cfg.drain_timeout, Duration::from_secs(5));
+            assert_eq!(cfg.defer_accept, Some(Duration::from_secs(3)));
+            assert_eq!(cfg.trigger, Trigger::Level);
+            assert_eq!(cfg.accept, Accept::EventLoop);
+            assert_eq!(cfg.extra_listeners, vec![
+                Listen::Tcp { addr: "[::1]:8080".parse().unwrap() },
+                Listen::Unix { path: PathBuf::from("/run/hydrogen.sock"), mode: Some(0o660) },
+                Listen::AbstractUnix { name: "hydrogen".to_string() }
+            ]);
+            assert_eq!(cfg.connection.keepalive_idle, Some(Duration::from_secs(60)));
+            assert_eq!(cfg.connection.user_timeout, Some(Duration::from_millis(1500)));
+
+            // Whatever is left out keeps its default
+            assert_eq!(cfg.backlog, Config::default().backlog);
+            assert_eq!(cfg.connection.nodelay, None);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn deserializing_rejects_unknown_options() {
+        assert!(toml::from_str::<Config>("max_thread = 2").is_err());
+    }
 }
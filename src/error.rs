@@ -0,0 +1,135 @@
+// Copyright 2015 Nathan Sizemore <nathanrsizemore@gmail.com>
+//
+// This Source Code Form is subject to the terms of the
+// Mozilla Public License, v. 2.0. If a copy of the MPL was not
+// distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+
+
+use std::fmt;
+use std::error;
+use std::io::{Error, ErrorKind};
+
+
+/// Errors that stop, or keep from starting, a server.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum HydrogenError {
+    /// The `Config` passed to `begin` is invalid.
+    Config(ConfigError),
+    /// Creating, binding or configuring the listening socket failed.
+    Bind(Error),
+    /// The sockets passed through `LISTEN_FDS` could not be adopted.
+    SocketActivation(Error),
+    /// Creating an eventfd used to wake the server's threads failed.
+    EventFd(Error),
+    /// `epoll_create` failed, or with `Backend::Poll`, creating the poller's eventfd.
+    EpollCreate(Error),
+    /// Blocking the watched signals, or creating or registering their signalfd, failed.
+    SignalFd(Error),
+    /// `epoll_wait`, or `poll` or `io_uring_enter` with the other backends, failed with
+    /// something other than `EINTR`.
+    EpollWait(Error),
+    /// Waiting on or accepting from the listening socket failed with an unrecoverable error.
+    Accept(Error),
+    /// Spawning one of the server's threads failed.
+    Spawn(Error),
+    /// One of the server's threads panicked.
+    Panic
+}
+
+impl fmt::Display for HydrogenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HydrogenError::Config(ref e) => write!(f, "Invalid config: {}", e),
+            HydrogenError::Bind(ref e) => write!(f, "Creating listener: {}", e),
+            HydrogenError::SocketActivation(ref e) => write!(f, "Adopting LISTEN_FDS: {}", e),
+            HydrogenError::EventFd(ref e) => write!(f, "Creating eventfd: {}", e),
+            HydrogenError::EpollCreate(ref e) => write!(f, "Creating epoll instance: {}", e),
+            HydrogenError::SignalFd(ref e) => write!(f, "Creating signalfd: {}", e),
+            HydrogenError::EpollWait(ref e) => write!(f, "During epoll_wait: {}", e),
+            HydrogenError::Accept(ref e) => write!(f, "Accepting connection: {}", e),
+            HydrogenError::Spawn(ref e) => write!(f, "Spawning thread: {}", e),
+            HydrogenError::Panic => write!(f, "A server thread panicked")
+        }
+    }
+}
+
+impl error::Error for HydrogenError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            HydrogenError::Config(ref e) => Some(e),
+            HydrogenError::Bind(ref e) |
+            HydrogenError::SocketActivation(ref e) |
+            HydrogenError::EventFd(ref e) |
+            HydrogenError::EpollCreate(ref e) |
+            HydrogenError::SignalFd(ref e) |
+            HydrogenError::EpollWait(ref e) |
+            HydrogenError::Accept(ref e) |
+            HydrogenError::Spawn(ref e) => Some(e),
+            HydrogenError::Panic => None
+        }
+    }
+}
+
+/// Invalid `Config` options, returned from `ConfigBuilder::build` and `Config::validate`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ConfigError {
+    /// `max_threads` is 0.
+    MaxThreads,
+    /// `reactors` is 0.
+    Reactors,
+    /// `max_events` is 0, or too large for `epoll_wait`.
+    MaxEvents,
+    /// `accept_batch` is 0.
+    AcceptBatch,
+    /// An address passed to `ConfigBuilder` could not be resolved. Contains the reason.
+    Addr(String),
+    /// An environment variable read by `Config::from_env` holds an invalid value.
+    /// Contains the variable's name, then its value.
+    Env(String, String)
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::MaxThreads => write!(f, "max_threads must be at least 1"),
+            ConfigError::Reactors => write!(f, "reactors must be at least 1"),
+            ConfigError::MaxEvents => write!(f, "max_events must be between 1 and i32::MAX"),
+            ConfigError::AcceptBatch => write!(f, "accept_batch must be at least 1"),
+            ConfigError::Addr(ref e) => write!(f, "Resolving addr: {}", e),
+            ConfigError::Env(ref name, ref value) => write!(f, "Invalid {}: {}", name, value)
+        }
+    }
+}
+
+impl error::Error for ConfigError {}
+
+/// Reason passed to `Handler::on_connection_removed` for connections closed because the server
+/// is shutting down.
+///
+/// It is wrapped in a `std::io::Error` of kind `ErrorKind::ConnectionAborted`, use
+/// `is_server_shutdown` to tell it apart from a peer abort.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServerShutdown;
+
+impl ServerShutdown {
+    pub(crate) fn into_io_error(self) -> Error {
+        Error::new(ErrorKind::ConnectionAborted, self)
+    }
+}
+
+impl fmt::Display for ServerShutdown {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Server shutting down")
+    }
+}
+
+impl error::Error for ServerShutdown {}
+
+/// Returns true if `err` was given to `Handler::on_connection_removed` because the server is
+/// shutting down.
+pub fn is_server_shutdown(err: &Error) -> bool {
+    err.get_ref().is_some_and(|e| e.is::<ServerShutdown>())
+}
@@ -0,0 +1,248 @@
+// Copyright 2015 Nathan Sizemore <nathanrsizemore@gmail.com>
+//
+// This Source Code Form is subject to the terms of the
+// Mozilla Public License, v. 2.0. If a copy of the MPL was not
+// distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+
+
+use std::io::{Error, ErrorKind};
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use config::Config;
+use error::HydrogenError;
+use handoff;
+use pool::ThreadPool;
+use types::{EventHandler, ListenerFds, ListenerId, Stats, StopSignal};
+
+
+/// Snapshot of a server's state, returned from `ServerHandle::stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ServerStats {
+    /// True between `ServerHandle::pause_accept` and `ServerHandle::resume_accept`
+    pub accept_paused: bool,
+    /// Connections accepted and handed to `Handler::on_new_connection` since the server
+    /// started, not counting those closed by `Handler::on_connection_options_failed`
+    pub accepted: u64,
+    /// Connections currently open
+    pub connections: usize,
+    /// Threads in the I/O thread pool
+    pub worker_threads: usize,
+    /// Jobs running on the I/O thread pool
+    pub active_jobs: usize,
+    /// Jobs waiting for a free thread in the I/O thread pool
+    pub queued_jobs: usize
+}
+
+/// Handle to a running server, returned from `hydrogen::begin`.
+///
+/// Dropping the handle does not stop the server, it only detaches from it. Call `shutdown` or
+/// `drain` followed by `join` to stop the server and wait for all of its threads to exit.
+pub struct ServerHandle {
+    /// Stop request shared with the server's threads
+    stop: Arc<StopSignal>,
+    /// The listening sockets, while they are open
+    listener_fds: ListenerFds,
+    /// Names of socket activated listeners, indexed by `ListenerId`
+    listener_names: Vec<Option<String>>,
+    /// Counters and flags shared with the server's threads
+    stats: Arc<Stats>,
+    /// The I/O thread pool, shared with the event loop and sentinel
+    thread_pool: ThreadPool,
+    /// The "Event Loop" threads, one per reactor. Each owns its reactor's listener thread and
+    /// sentinel.
+    event_loops: Vec<JoinHandle<Result<(), HydrogenError>>>,
+    /// The consumer's handler, released once everything has exited
+    handler: EventHandler
+}
+
+impl ServerHandle {
+    pub(crate) fn new(stop: Arc<StopSignal>,
+                      listener_fds: ListenerFds,
+                      listener_names: Vec<Option<String>>,
+                      stats: Arc<Stats>,
+                      thread_pool: ThreadPool,
+                      event_loops: Vec<JoinHandle<Result<(), HydrogenError>>>,
+                      handler: EventHandler)
+                      -> ServerHandle
+    {
+        ServerHandle {
+            stop: stop,
+            listener_fds: listener_fds,
+            listener_names: listener_names,
+            stats: stats,
+            thread_pool: thread_pool,
+            event_loops: event_loops,
+            handler: handler
+        }
+    }
+
+    /// Requests the server to stop.
+    ///
+    /// Every connection is closed as soon as the event loop notices, without waiting on queued
+    /// I/O. If a `drain` is in progress, it is cut short.
+    ///
+    /// This returns immediately and may be called from any thread, any number of times.
+    /// Use `join` to wait for the server to finish stopping.
+    pub fn shutdown(&self) {
+        self.stop.request_shutdown();
+    }
+
+    /// Requests the server to stop gracefully, taking no longer than `timeout`.
+    ///
+    /// The listener is stopped first. Each open connection is then passed to
+    /// `Handler::on_connection_draining`, and queued I/O and pending write backlogs are allowed
+    /// to finish. Whatever is left once everything is idle, or once `timeout` has elapsed, is
+    /// closed.
+    ///
+    /// Has no effect if a stop was already requested.
+    pub fn drain(&self, timeout: Duration) {
+        self.stop.request_drain(timeout);
+    }
+
+    /// Stops accepting new connections, without closing the listening sockets.
+    ///
+    /// Connection attempts keep queueing in the kernel's listen backlog, up to its limit, and
+    /// are accepted once `resume_accept` is called. Open connections are not affected.
+    pub fn pause_accept(&self) {
+        if !self.stats.accept_paused.swap(true, Ordering::SeqCst) {
+            info!("Accepting paused");
+            self.stop.wake_all();
+        }
+    }
+
+    /// Resumes accepting after `pause_accept`, starting with whatever queued up in the
+    /// listen backlog meanwhile.
+    pub fn resume_accept(&self) {
+        if self.stats.accept_paused.swap(false, Ordering::SeqCst) {
+            info!("Accepting resumed");
+            self.stop.wake_all();
+        }
+    }
+
+    /// Returns the name the service manager gave the listener through `LISTEN_FDNAMES`, if
+    /// the server was socket activated and the listener was named.
+    pub fn listener_name(&self, id: ListenerId) -> Option<&str> {
+        match self.listener_names.get(id.0) {
+            Some(Some(name)) => Some(name),
+            _ => None
+        }
+    }
+
+    /// Returns a snapshot of the server's state.
+    pub fn stats(&self) -> ServerStats {
+        ServerStats {
+            accept_paused: self.stats.accept_paused.load(Ordering::SeqCst),
+            accepted: self.stats.accepted.load(Ordering::SeqCst),
+            connections: self.stats.connections.load(Ordering::SeqCst),
+            worker_threads: self.thread_pool.max_count(),
+            active_jobs: self.thread_pool.active_count(),
+            queued_jobs: self.thread_pool.queued_count()
+        }
+    }
+
+    /// Grows or shrinks the I/O thread pool to `num_threads`.
+    ///
+    /// Growing takes effect immediately. When shrinking, busy threads finish their current job
+    /// before exiting.
+    pub fn set_worker_threads(&self, num_threads: usize) -> Result<(), Error> {
+        if num_threads == 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "Worker threads must be at least 1"));
+        }
+
+        info!("Resizing I/O threadpool to {} threads", num_threads);
+        self.thread_pool.set_num_threads(num_threads)
+    }
+
+    /// Applies the reloadable items of `cfg` to the running server. Every other item is
+    /// ignored, as it only takes effect on start.
+    ///
+    /// Currently reloadable: `max_threads`.
+    pub fn reload_config(&self, cfg: &Config) -> Result<(), Error> {
+        if cfg.max_threads != self.thread_pool.max_count() {
+            self.set_worker_threads(cfg.max_threads)?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends duplicates of the listening sockets over `stream`, for a successor process to
+    /// receive with `hydrogen::receive_listeners`.
+    ///
+    /// Both processes accept from the same sockets until this one stops, so a restart can be
+    /// done without refusing connections: start the successor with the received listeners
+    /// through `hydrogen::begin_with_listeners`, then call `drain` here.
+    ///
+    /// Fails with `ErrorKind::InvalidInput` when the server runs several `Config::reactors`, as
+    /// each of them accepts from its own sockets and the connections queued on those the
+    /// successor does not serve would be reset.
+    pub fn send_listeners(&self, stream: &UnixStream) -> Result<(), Error> {
+        if self.event_loops.len() > 1 {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                                  "Listeners of several reactors can not be handed off"));
+        }
+
+        // Held while sending, so the listener thread can not close the fds underneath us
+        let mut listeners = match self.listener_fds.lock() {
+            Ok(g) => g,
+            Err(p) => p.into_inner()
+        };
+
+        if listeners.fds.is_empty() {
+            return Err(Error::new(ErrorKind::NotConnected, "Listener is closed"));
+        }
+
+        handoff::send_fds(stream, &listeners.fds[..])?;
+
+        // The successor serves our Unix socket files now, so they outlive us
+        listeners.handed_off = true;
+
+        Ok(())
+    }
+
+    /// Returns true if `shutdown` or `drain` has been called.
+    pub fn is_shutdown(&self) -> bool {
+        self.stop.is_requested()
+    }
+
+    /// Blocks until every reactor's listener thread, event loop and I/O sentinel, and every
+    /// thread of the I/O thread pool, have exited.
+    ///
+    /// This does not request a shutdown by itself, so without a prior call to `shutdown` it
+    /// blocks until the server stops on its own, which only happens on an unrecoverable error.
+    /// That error is returned here.
+    pub fn join(self) -> Result<(), HydrogenError> {
+        let mut result = Ok(());
+        let mut panicked = false;
+        for event_loop in self.event_loops.into_iter() {
+            match event_loop.join() {
+                Ok(r) => result = result.and(r),
+                Err(_) => {
+                    error!("Event loop exited with a panic");
+                    // The other reactors would not stop on their own
+                    self.stop.request();
+                    panicked = true;
+                }
+            }
+        }
+        if panicked {
+            return Err(HydrogenError::Panic);
+        }
+
+        // The event loops waited on their jobs, this waits on the threads that ran them
+        self.thread_pool.join();
+
+        // Nothing references the handler anymore
+        let EventHandler(ptr) = self.handler;
+        unsafe {
+            drop(Box::from_raw(ptr));
+        }
+
+        result
+    }
+}
@@ -0,0 +1,232 @@
+// Copyright 2015 Nathan Sizemore <nathanrsizemore@gmail.com>
+//
+// This Source Code Form is subject to the terms of the
+// Mozilla Public License, v. 2.0. If a copy of the MPL was not
+// distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+
+
+use std::mem;
+use std::ptr;
+use std::io::{Error, ErrorKind};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+
+use libc;
+
+use activation;
+use socket::Listener;
+
+
+// Most listening sockets sent in a single handoff
+const MAX_FDS: usize = 64;
+
+/// Receives the listening sockets sent by `ServerHandle::send_listeners` in another process.
+///
+/// The returned listeners are meant to be passed to `hydrogen::begin_with_listeners`. The
+/// sending process keeps accepting on the same sockets until it stops, so no connection attempt
+/// is refused during the switch.
+pub fn receive_listeners(stream: &UnixStream) -> Result<Vec<Listener>, Error> {
+    let fds = recv_fds(stream)?;
+
+    // Make sure we were actually handed listening sockets
+    for fd in fds.iter() {
+        if let Err(err) = activation::check_listening(*fd) {
+            close_all(&fds);
+            return Err(err);
+        }
+    }
+
+    let mut listeners = Vec::<Listener>::with_capacity(fds.len());
+    for (x, fd) in fds.iter().enumerate() {
+        match unsafe { Listener::from_raw_fd(*fd) } {
+            Ok(l) => listeners.push(l),
+            Err(err) => {
+                // The ones already wrapped are closed when dropped
+                close_all(&fds[x..]);
+                return Err(err);
+            }
+        }
+    }
+
+    Ok(listeners)
+}
+
+fn close_all(fds: &[RawFd]) {
+    for fd in fds.iter() {
+        unsafe {
+            libc::close(*fd);
+        }
+    }
+}
+
+/// Sends `fds` over `stream` as SCM_RIGHTS ancillary data.
+pub fn send_fds(stream: &UnixStream, fds: &[RawFd]) -> Result<(), Error> {
+    if fds.is_empty() || fds.len() > MAX_FDS {
+        return Err(Error::new(ErrorKind::InvalidInput,
+                              format!("Can send between 1 and {} fds", MAX_FDS)));
+    }
+
+    unsafe {
+        // At least one byte of real data has to go along with the fds
+        let mut byte = 0u8;
+        let mut iov = libc::iovec {
+            iov_base: &mut byte as *mut u8 as *mut libc::c_void,
+            iov_len: 1
+        };
+
+        let fds_size = mem::size_of_val(fds) as u32;
+        let space = libc::CMSG_SPACE(fds_size) as usize;
+        let mut cmsg_buf = vec![0u8; space];
+
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = space as _;
+
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(fds_size) as _;
+        let data = libc::CMSG_DATA(cmsg) as *mut RawFd;
+        for (x, fd) in fds.iter().enumerate() {
+            ptr::write_unaligned(data.add(x), *fd);
+        }
+
+        loop {
+            let result = libc::sendmsg(stream.as_raw_fd(), &msg, libc::MSG_NOSIGNAL);
+            if result >= 0 {
+                return Ok(());
+            }
+
+            let err = Error::last_os_error();
+            if err.kind() != ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
+}
+
+/// Receives the fds sent with `send_fds`. They are created close-on-exec.
+pub fn recv_fds(stream: &UnixStream) -> Result<Vec<RawFd>, Error> {
+    unsafe {
+        let mut byte = 0u8;
+        let mut iov = libc::iovec {
+            iov_base: &mut byte as *mut u8 as *mut libc::c_void,
+            iov_len: 1
+        };
+
+        let space = libc::CMSG_SPACE((MAX_FDS * mem::size_of::<RawFd>()) as u32) as usize;
+        let mut cmsg_buf = vec![0u8; space];
+
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = space as _;
+
+        loop {
+            let result = libc::recvmsg(stream.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC);
+            if result > 0 {
+                break;
+            }
+            if result == 0 {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "Peer closed before sending fds"));
+            }
+
+            let err = Error::last_os_error();
+            if err.kind() != ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if cmsg.is_null()
+            || (*cmsg).cmsg_level != libc::SOL_SOCKET
+            || (*cmsg).cmsg_type != libc::SCM_RIGHTS
+        {
+            return Err(Error::new(ErrorKind::InvalidData, "No fds received"));
+        }
+
+        let header_len = libc::CMSG_LEN(0) as usize;
+        let num_fds = ((*cmsg).cmsg_len as usize - header_len) / mem::size_of::<RawFd>();
+        let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+
+        let mut fds = Vec::<RawFd>::with_capacity(num_fds);
+        for x in 0..num_fds {
+            fds.push(ptr::read_unaligned(data.add(x)));
+        }
+
+        Ok(fds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+    use std::net::TcpListener;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::os::unix::net::UnixStream;
+
+    use libc;
+
+    use super::{receive_listeners, recv_fds, send_fds, MAX_FDS};
+    use socket::Listener;
+
+    #[test]
+    fn fds_round_trip() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let a = TcpListener::bind("127.0.0.1:0").unwrap();
+        let b = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        send_fds(&tx, &[a.as_raw_fd(), b.as_raw_fd()]).unwrap();
+        let fds = recv_fds(&rx).unwrap();
+        assert_eq!(fds.len(), 2);
+
+        // New fds for the same sockets, in the order they were sent
+        for (fd, sent) in fds.iter().zip([&a, &b].iter()) {
+            assert!(*fd != sent.as_raw_fd());
+            let flags = unsafe { libc::fcntl(*fd, libc::F_GETFD) };
+            assert!(flags & libc::FD_CLOEXEC != 0);
+
+            let received = unsafe { TcpListener::from_raw_fd(*fd) };
+            assert_eq!(received.local_addr().unwrap(), sent.local_addr().unwrap());
+        }
+    }
+
+    #[test]
+    fn send_fds_checks_the_count() {
+        let (tx, _rx) = UnixStream::pair().unwrap();
+        let err = send_fds(&tx, &[]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+        let fds = vec![tx.as_raw_fd(); MAX_FDS + 1];
+        let err = send_fds(&tx, &fds[..]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn recv_fds_fails_when_the_peer_hangs_up() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        drop(tx);
+        assert_eq!(recv_fds(&rx).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn receive_listeners_takes_listening_sockets_only() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        send_fds(&tx, &[listener.as_raw_fd()]).unwrap();
+        match receive_listeners(&rx).unwrap().pop() {
+            Some(Listener::Tcp(l)) => assert_eq!(l.local_addr().unwrap(),
+                                                 listener.local_addr().unwrap()),
+            other => panic!("expected a TCP listener, got {:?}", other)
+        }
+
+        // A connected socket is not one
+        let (other, _peer) = UnixStream::pair().unwrap();
+        send_fds(&tx, &[other.as_raw_fd()]).unwrap();
+        assert!(receive_listeners(&rx).is_err());
+    }
+}
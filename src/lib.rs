@@ -33,7 +33,7 @@
 //! extern crate simple_stream as ss;
 //!
 //! use hydrogen;
-//! use hydrogen::{Stream as HydrogenStream, HydrogenSocket};
+//! use hydrogen::{Stream as HydrogenStream, HydrogenSocket, ListenerId};
 //! use ss::frame::Frame;
 //! use ss::frame::simple::{SimpleFrame, SimpleFrameBuilder};
 //! use ss::{Socket, Plain, NonBlocking, SocketOptions};
@@ -78,7 +78,9 @@
 //!
 //!     }
 //!
-//!     fn on_new_connection(&mut self, fd: RawFd) -> Arc<UnsafeCell<HydrogenStream>> {
+//!     fn on_new_connection(&mut self, fd: RawFd, listener: ListenerId)
+//!         -> Arc<UnsafeCell<HydrogenStream>>
+//!     {
 //!
 //!     }
 //!
@@ -93,12 +95,16 @@
 //!
 //!
 //! fn main() {
-//!     hydrogen::begin(Server, hydrogen::Config {
-//!         addr: "0.0.0.0".to_string(),
-//!         port: 1337,
-//!         max_threads: 8,
-//!         pre_allocated: 100000
-//!     });
+//!     let cfg = hydrogen::Config::builder()
+//!         .port(1337)
+//!         .handle_signals(true)
+//!         .drain_timeout(Duration::from_secs(30))
+//!         .build()
+//!         .unwrap();
+//!     let server = hydrogen::begin(Box::new(Server), cfg).unwrap();
+//!
+//!     // `server.shutdown()` may be called from any thread to stop the server
+//!     server.join().unwrap();
 //! }
 //!
 //! ```
@@ -116,23 +122,44 @@
 extern crate log;
 extern crate libc;
 extern crate errno;
-extern crate threadpool;
-extern crate simple_slab;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "io-uring")]
+extern crate io_uring;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
+#[cfg(all(test, feature = "serde"))]
+extern crate toml;
 
 
 use std::io::Error;
 use std::sync::Arc;
 use std::cell::UnsafeCell;
 use std::os::unix::io::{RawFd, AsRawFd};
-use std::sync::atomic::AtomicBool;
 
 
-pub use config::Config;
-pub use types::HydrogenSocket;
+pub use config::{Accept, Backend, Config, ConfigBuilder, ConnectionOptions, Listen, Trigger};
+pub use error::{ConfigError, HydrogenError, ServerShutdown, is_server_shutdown};
+pub use handle::{ServerHandle, ServerStats};
+pub use handoff::receive_listeners;
+pub use signal::Signal;
+pub use socket::Listener;
+pub use types::{HydrogenSocket, ListenerId};
 
 mod types;
 mod server;
+mod activation;
 mod config;
+mod error;
+mod handle;
+mod handoff;
+mod poller;
+mod pool;
+mod signal;
+mod slab;
+mod socket;
+#[cfg(test)]
+mod test_util;
 
 
 /// Trait object responsible for handling reported I/O events.
@@ -150,19 +177,62 @@ pub trait Stream : AsRawFd + Send + Sync {
     /// This method is called when any error, other than `ErrorKind::WouldBlock`, is returned from
     /// a `recv` or `send` call.
     fn shutdown(&mut self) -> Result<(), Error>;
+    /// Opts this connection into completion I/O with `Backend::IoUring`: the poller receives
+    /// into buffers it provides to the kernel and sends on its own, calling `decode` and
+    /// `encode` instead of `recv` and `send`. Ignored by the other backends, and by kernels
+    /// that can not provide buffers.
+    fn completion_io(&self) -> bool {
+        false
+    }
+    /// Called with what was received on a connection opted into completion I/O, returning
+    /// every complete message in it. Anything left over has to be kept for the next call.
+    fn decode(&mut self, buf: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+        Ok(vec![buf.to_vec()])
+    }
+    /// Called with each buffer a `HydrogenSocket` sends on a connection opted into completion
+    /// I/O, returning the bytes to queue for sending.
+    fn encode(&mut self, buf: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(buf.to_vec())
+    }
 }
 
 /// Events reported to lib consumer.
 pub trait Handler {
-    /// This method is called once the listening RawFd has been created.
+    /// This method is called once for each listening RawFd, once it has been created.
     ///
-    /// It should be used to set/remove any flags on the underlying RawFd before `listen` is
-    /// called on the fd.
+    /// By now the fd is bound and listening, so it can only be used for options that still
+    /// take effect afterwards. Anything that has to be set before `bind` or `listen` belongs
+    /// in `Config` or `on_socket_created`.
     fn on_server_created(&mut self, fd: RawFd);
-    /// This method is called whenever `accept` returns a new TCP connection.
+    /// This method is called for each socket the server is about to listen on, before `bind`
+    /// and `listen` are called on it, and after the options from `Config` have been set.
+    ///
+    /// It is not called for listeners passed to `begin_with_listeners` or adopted through
+    /// socket activation, which are already bound. Returning an error stops `begin`, which
+    /// returns it as `HydrogenError::Bind`.
+    #[allow(unused_variables)]
+    fn on_socket_created(&mut self, fd: RawFd, listener: ListenerId) -> Result<(), Error> {
+        Ok(())
+    }
+    /// This method is called whenever `accept` returns a new TCP or Unix domain connection,
+    /// with the id of the listener it was accepted from.
+    ///
+    /// The fd is already non-blocking, with the options from `Config::connection` set. The
+    /// returned trait object is added to the connection pool and the epoll interest list.
+    /// It is called on the listener thread, or on the event loop with `Accept::EventLoop`.
+    fn on_new_connection(&mut self, fd: RawFd, listener: ListenerId)
+        -> Arc<UnsafeCell<dyn Stream>>;
+    /// This method is called when setting an option from `Config::connection` on a newly
+    /// accepted connection fails, before `on_new_connection`.
     ///
-    /// The returned trait object is added to the connection pool and the epoll interest list.
-    fn on_new_connection(&mut self, fd: RawFd) -> Arc<UnsafeCell<dyn Stream>>;
+    /// Returning `true` keeps the connection, without the options that were not set yet.
+    /// Returning `false` closes it, and `on_new_connection` is not called for it.
+    #[allow(unused_variables)]
+    fn on_connection_options_failed(&mut self, fd: RawFd, listener: ListenerId, err: Error)
+        -> bool
+    {
+        true
+    }
     /// This method is called whenever the `recv` call returns an Ok(_) result.
     fn on_data_received(&mut self, socket: HydrogenSocket, buf: Vec<u8>);
     /// This method is called after a stream has been removed from the connection poll and epoll
@@ -170,12 +240,54 @@ pub trait Handler {
     ///
     /// At the time of this call, the underlying fd has been shutdown and closed. No system level
     /// shutdown is needed, only application level cleanup.
+    ///
+    /// Connections closed because the server is stopping are reported with a `ServerShutdown`
+    /// reason, see `hydrogen::is_server_shutdown`.
     fn on_connection_removed(&mut self, fd: RawFd, err: Error);
+    /// This method is called once for each open connection when `ServerHandle::drain` is
+    /// called, before any connection is closed.
+    ///
+    /// It can be used to send a final message to the peer. Writes that return
+    /// `ErrorKind::WouldBlock` are flushed before the connection is closed, provided the drain
+    /// deadline has not passed.
+    #[allow(unused_variables)]
+    fn on_connection_draining(&mut self, socket: HydrogenSocket) { }
+    /// This method is called for every signal received while `Config::handle_signals` is set.
+    ///
+    /// Returning `true` runs the default action afterwards: `Signal::Term` and `Signal::Int`
+    /// drain the server for `Config::drain_timeout`, or stop it immediately if it is already
+    /// stopping, and `Signal::Hup` calls `on_reload`. Returning `false` skips it.
+    #[allow(unused_variables)]
+    fn on_signal(&mut self, signal: Signal) -> bool { true }
+    /// This method is called when SIGHUP is received and `on_signal` did not suppress the
+    /// default action.
+    fn on_reload(&mut self) { }
 }
 
 /// Starts the server with the passed configuration and handler.
-pub fn begin<T>(handler: Box<T>, cfg: Config, drop_handler: Option<Arc<AtomicBool>>)
+///
+/// The listening socket is bound before returning, all other work happens on background
+/// threads. The returned `ServerHandle` is used to stop the server and wait for it to exit.
+///
+/// Any failure to bind or to create the server's resources and threads is returned as a
+/// `HydrogenError`, nothing is left running in that case.
+pub fn begin<T>(handler: Box<T>, cfg: Config) -> Result<ServerHandle, HydrogenError>
     where T: Handler + Send + Sync + 'static
 {
-    server::begin(handler, cfg, drop_handler);
+    server::begin(handler, cfg, Vec::new())
+}
+
+/// Starts the server on already listening sockets, rather than binding `cfg.addr` and
+/// `cfg.extra_listeners`. Listener ids follow the order of `listeners`, which may be
+/// `TcpListener`s, `UnixListener`s or `Listener`s.
+///
+/// This is the receiving end of a restart: the listeners come from
+/// `hydrogen::receive_listeners`, sent by the previous process's
+/// `ServerHandle::send_listeners`. It behaves like `begin` in every other way.
+pub fn begin_with_listeners<T, L>(handler: Box<T>, cfg: Config, listeners: Vec<L>)
+    -> Result<ServerHandle, HydrogenError>
+    where T: Handler + Send + Sync + 'static,
+          L: Into<Listener>
+{
+    server::begin(handler, cfg, listeners.into_iter().map(|l| l.into()).collect())
 }
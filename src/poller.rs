@@ -0,0 +1,1563 @@
+// Copyright 2015 Nathan Sizemore <nathanrsizemore@gmail.com>
+//
+// This Source Code Form is subject to the terms of the
+// Mozilla Public License, v. 2.0. If a copy of the MPL was not
+// distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+
+
+#[cfg(feature = "io-uring")]
+use std::alloc::{self, Layout};
+use std::cmp;
+use std::collections::HashMap;
+#[cfg(feature = "io-uring")]
+use std::collections::{HashSet, VecDeque};
+use std::io::Error;
+#[cfg(feature = "io-uring")]
+use std::io::ErrorKind;
+#[cfg(feature = "io-uring")]
+use std::mem;
+use std::os::unix::io::RawFd;
+#[cfg(feature = "io-uring")]
+use std::ptr;
+#[cfg(feature = "io-uring")]
+use std::sync::TryLockError;
+use std::sync::Mutex;
+#[cfg(feature = "io-uring")]
+use std::sync::atomic::AtomicU16;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use libc;
+
+use config::Backend;
+use types::Waker;
+
+
+/// Readiness notification backend, see `Config::backend`.
+///
+/// Registrations and events are described the way epoll does: with the `EPOLL*` flags, and
+/// with the token an fd was registered with reported as `epoll_event.u64`.
+pub trait Poller: Send + Sync {
+    /// Adds `fd` to the interest list, reporting `events` with `token` as `epoll_event.u64`.
+    fn add(&self, fd: RawFd, events: i32, token: u64) -> Result<(), Error>;
+
+    /// Replaces the events and token `fd` is registered with, re-arming it after an
+    /// `EPOLLONESHOT` event was reported.
+    fn modify(&self, fd: RawFd, events: i32, token: u64) -> Result<(), Error>;
+
+    /// Removes `fd` from the interest list.
+    fn delete(&self, fd: RawFd) -> Result<(), Error>;
+
+    /// Waits up to `timeout` milliseconds, or indefinitely if it is negative, for events,
+    /// returning how many were written to `buf`.
+    fn wait(&self, buf: &mut [libc::epoll_event], timeout: i32) -> Result<usize, Error>;
+
+    /// Called just before `fd` is closed. Epoll forgets a closed fd on its own, so this only
+    /// matters to backends that would otherwise keep it open.
+    fn closing(&self, _fd: RawFd) {}
+
+    /// Adds the listening socket `fd` to the interest list the way `add` does. A backend that
+    /// accepts connections itself may do so instead, reporting `events` with `token` while
+    /// it holds connections for `accept` to take.
+    fn add_listener(&self, fd: RawFd, events: i32, token: u64) -> Result<(), Error> {
+        self.add(fd, events, token)
+    }
+
+    /// Takes a connection accepted on the listener `fd`, failing with `WouldBlock` once there
+    /// are none left. Returns None if the backend does not accept on `fd`, in which case
+    /// connections are accepted with `accept(2)`.
+    fn accept(&self, _fd: RawFd) -> Option<Result<RawFd, Error>> {
+        None
+    }
+
+    /// Takes the connections accepted on the listener `fd` before it was deleted that
+    /// `accept` never handed out. Until then they are kept for the next `add_listener`.
+    fn take_accepted(&self, _fd: RawFd) -> Vec<RawFd> {
+        Vec::new()
+    }
+
+    /// Adds the connection `fd` to the interest list the way `add` does. A backend that does
+    /// I/O itself may receive on it instead, reporting `EPOLLIN` with `token` while it holds
+    /// data for `recv` to take, and send what `send` queues. Returns whether it does.
+    fn add_stream(&self, fd: RawFd, events: i32, token: u64) -> Result<bool, Error> {
+        self.add(fd, events, token).map(|_| false)
+    }
+
+    /// Takes everything received on the connection `fd` since the last time, failing with
+    /// `WouldBlock` if nothing was, and with the error receiving or sending failed with once
+    /// the rest was taken. Only called for connections `add_stream` receives on.
+    fn recv(&self, _fd: RawFd) -> Result<Vec<u8>, Error> {
+        Err(Error::from_raw_os_error(libc::EOPNOTSUPP))
+    }
+
+    /// Queues `buf` to be sent on the connection `fd` after everything queued before it.
+    /// Only called for connections `add_stream` receives on.
+    fn send(&self, _fd: RawFd, _buf: Vec<u8>) -> Result<(), Error> {
+        Err(Error::from_raw_os_error(libc::EOPNOTSUPP))
+    }
+
+    /// Returns whether anything queued with `send` on the connection `fd` is still unsent.
+    fn sending(&self, _fd: RawFd) -> bool {
+        false
+    }
+}
+
+/// Creates a poller of the `backend` kind.
+pub fn new(backend: Backend) -> Result<Box<dyn Poller>, Error> {
+    match backend {
+        Backend::Epoll => Ok(Box::new(Epoll::new()?)),
+        Backend::Poll => Ok(Box::new(Poll::new()?)),
+        #[cfg(feature = "io-uring")]
+        Backend::IoUring => match IoUring::new() {
+            Ok(p) => Ok(Box::new(p)),
+            Err(err) => {
+                warn!("io_uring unavailable, falling back to epoll: {}", err);
+                Ok(Box::new(Epoll::new()?))
+            }
+        }
+    }
+}
+
+/// An epoll instance.
+pub struct Epoll {
+    pub fd: RawFd
+}
+
+impl Epoll {
+    pub fn new() -> Result<Epoll, Error> {
+        let result = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if result < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        debug!("Epoll instance created with fd: {}", result);
+
+        Ok(Epoll { fd: result })
+    }
+
+    fn ctl(&self, op: i32, fd: RawFd, events: i32, token: u64) -> Result<(), Error> {
+        let result = unsafe {
+            libc::epoll_ctl(self.fd, op, fd, &mut libc::epoll_event {
+                events: events as u32,
+                u64: token
+            })
+        };
+        if result < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+
+impl Poller for Epoll {
+    fn add(&self, fd: RawFd, events: i32, token: u64) -> Result<(), Error> {
+        self.ctl(libc::EPOLL_CTL_ADD, fd, events, token)
+    }
+
+    fn modify(&self, fd: RawFd, events: i32, token: u64) -> Result<(), Error> {
+        self.ctl(libc::EPOLL_CTL_MOD, fd, events, token)
+    }
+
+    fn delete(&self, fd: RawFd) -> Result<(), Error> {
+        self.ctl(libc::EPOLL_CTL_DEL, fd, 0, 0)
+    }
+
+    fn wait(&self, buf: &mut [libc::epoll_event], timeout: i32) -> Result<usize, Error> {
+        let result = unsafe {
+            libc::epoll_wait(self.fd, buf.as_mut_ptr(), buf.len() as i32, timeout)
+        };
+        if result < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        Ok(result as usize)
+    }
+}
+
+impl Drop for Epoll {
+    fn drop(&mut self) {
+        debug!("Closing epoll instance with fd: {}", self.fd);
+        let result = unsafe { libc::close(self.fd) };
+        if result < 0 {
+            error!("Closing epoll instance: {}", Error::last_os_error());
+        }
+    }
+}
+
+/// `poll(2)` based poller, for when epoll is unavailable or misbehaves.
+///
+/// Registrations are kept in a table the `pollfd` array is rebuilt from on every wait. A
+/// change made while another thread waits wakes it through an eventfd, so it polls again
+/// with the change applied. `EPOLLONESHOT` is emulated by disarming an fd once an event is
+/// reported for it. `EPOLLET` and `EPOLLEXCLUSIVE` are ignored, every fd is level-triggered.
+///
+/// Unlike with epoll, closing an fd does not remove it. An fd found closed is dropped from
+/// the table, and adding an fd that is still in it replaces the stale registration, as the
+/// fd number can only have been reused after the old one was closed.
+pub struct Poll {
+    registrations: Mutex<HashMap<RawFd, Registration>>,
+    /// Source of `Registration::serial`
+    serials: AtomicU64,
+    /// Interrupts a `wait` in progress when the registrations change
+    waker: Waker
+}
+
+#[derive(Clone, Copy)]
+struct Registration {
+    events: i32,
+    token: u64,
+    /// False once an `EPOLLONESHOT` event was reported, until the fd is modified
+    armed: bool,
+    /// Changes on every add and modify, so a wait ignores events for an fd registered again
+    /// while it was polling
+    serial: u64
+}
+
+impl Poll {
+    pub fn new() -> Result<Poll, Error> {
+        Ok(Poll {
+            registrations: Mutex::new(HashMap::new()),
+            serials: AtomicU64::new(0),
+            waker: Waker::new()?
+        })
+    }
+
+    fn register(&self, fd: RawFd, events: i32, token: u64, replace: bool) -> Result<(), Error> {
+        let serial = self.serials.fetch_add(1, Ordering::SeqCst);
+
+        { // Mutex lock
+            let mut registrations = match self.registrations.lock() {
+                Ok(g) => g,
+                Err(p) => p.into_inner()
+            };
+            if replace && !registrations.contains_key(&fd) {
+                return Err(Error::from_raw_os_error(libc::ENOENT));
+            }
+
+            registrations.insert(fd, Registration {
+                events: events,
+                token: token,
+                armed: true,
+                serial: serial
+            });
+        } // Mutex unlock
+
+        self.waker.wake();
+
+        Ok(())
+    }
+}
+
+impl Poller for Poll {
+    fn add(&self, fd: RawFd, events: i32, token: u64) -> Result<(), Error> {
+        self.register(fd, events, token, false)
+    }
+
+    fn modify(&self, fd: RawFd, events: i32, token: u64) -> Result<(), Error> {
+        self.register(fd, events, token, true)
+    }
+
+    fn delete(&self, fd: RawFd) -> Result<(), Error> {
+        { // Mutex lock
+            let mut registrations = match self.registrations.lock() {
+                Ok(g) => g,
+                Err(p) => p.into_inner()
+            };
+            if registrations.remove(&fd).is_none() {
+                return Err(Error::from_raw_os_error(libc::ENOENT));
+            }
+        } // Mutex unlock
+
+        self.waker.wake();
+
+        Ok(())
+    }
+
+    fn wait(&self, buf: &mut [libc::epoll_event], timeout: i32) -> Result<usize, Error> {
+        let deadline = if timeout < 0 {
+            None
+        } else {
+            Some(Instant::now() + Duration::from_millis(timeout as u64))
+        };
+
+        let mut pollfds = Vec::<libc::pollfd>::new();
+        let mut serials = Vec::<u64>::new();
+        loop {
+            // The waker goes first, so it is always polled
+            pollfds.clear();
+            serials.clear();
+            pollfds.push(libc::pollfd { fd: self.waker.fd, events: libc::POLLIN, revents: 0 });
+            serials.push(0);
+            { // Mutex lock
+                let registrations = match self.registrations.lock() {
+                    Ok(g) => g,
+                    Err(p) => p.into_inner()
+                };
+                for (&fd, registration) in registrations.iter() {
+                    if registration.armed {
+                        pollfds.push(libc::pollfd {
+                            fd: fd,
+                            events: to_poll_events(registration.events),
+                            revents: 0
+                        });
+                        serials.push(registration.serial);
+                    }
+                }
+            } // Mutex unlock
+
+            let remaining = match deadline {
+                Some(d) => {
+                    let left = d.saturating_duration_since(Instant::now()).as_millis();
+                    cmp::min(left, i32::MAX as u128) as i32
+                }
+                None => -1
+            };
+            let result = unsafe {
+                libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, remaining)
+            };
+            if result < 0 {
+                return Err(Error::last_os_error());
+            }
+            if result == 0 {
+                return Ok(0);
+            }
+            if pollfds[0].revents != 0 {
+                self.waker.reset();
+            }
+
+            let mut num_events = 0;
+            { // Mutex lock
+                let mut registrations = match self.registrations.lock() {
+                    Ok(g) => g,
+                    Err(p) => p.into_inner()
+                };
+                for (pollfd, &serial) in pollfds.iter().zip(serials.iter()).skip(1) {
+                    if pollfd.revents == 0 {
+                        continue;
+                    }
+                    if num_events == buf.len() {
+                        break;
+                    }
+
+                    let closed = match registrations.get_mut(&pollfd.fd) {
+                        Some(r) if r.armed && r.serial == serial => {
+                            if pollfd.revents & libc::POLLNVAL != 0 {
+                                true
+                            } else {
+                                buf[num_events] = libc::epoll_event {
+                                    events: from_poll_events(pollfd.revents),
+                                    u64: r.token
+                                };
+                                num_events += 1;
+
+                                if r.events & libc::EPOLLONESHOT != 0 {
+                                    r.armed = false;
+                                }
+                                false
+                            }
+                        }
+                        // Changed while we were polling, it is polled again as it is now
+                        _ => false
+                    };
+                    if closed {
+                        trace!("Dropping closed fd {} from poll", pollfd.fd);
+                        registrations.remove(&pollfd.fd);
+                    }
+                }
+            } // Mutex unlock
+
+            // Only woken for a change, or every event was outdated
+            let expired = match deadline {
+                Some(d) => Instant::now() >= d,
+                None => false
+            };
+            if num_events > 0 || expired {
+                return Ok(num_events);
+            }
+        }
+    }
+}
+
+/// io_uring based poller, see `Backend::IoUring`.
+///
+/// Every registration is a single-shot `IORING_OP_POLL_ADD`. Once it completes, an fd
+/// registered with `EPOLLONESHOT` stays disarmed until it is modified, any other fd is
+/// polled again, which keeps it level-triggered the way a multishot poll would not.
+///
+/// Listeners are accepted on with a multishot `IORING_OP_ACCEPT` instead, so the kernel
+/// accepts connections as they arrive and `accept` hands them out without a syscall. Kernels
+/// older than 5.19 reject it, after which listeners are polled like any other fd.
+///
+/// Connections added with `add_stream` are received on with a multishot `IORING_OP_RECV`
+/// into a ring of buffers provided to the kernel, each copied out and handed back as soon as
+/// it completes, until `recv` takes what was received. `send` queues buffers that are sent
+/// one `IORING_OP_SEND` at a time, in order. Reported events follow the registration's
+/// `EPOLLONESHOT` like a poll's would. Kernels older than 5.19 can not provide the buffers,
+/// so `add_stream` polls instead, and those older than 6.0 receive with single-shot recvs.
+///
+/// A request is cancelled when the thread that submitted it exits, so only the thread in
+/// `wait`, an event loop or listener thread that outlives its registrations, submits.
+/// Changes made from other threads, such as a `HydrogenSocket::send` re-arming a connection,
+/// are queued and the waiting thread is woken through an eventfd the ring polls.
+///
+/// Re-registering or deleting an fd cancels the request in flight for it, as the request
+/// holds a reference to the file that would keep a closed socket open. A buffer being sent
+/// when its connection is deleted is kept until the kernel is done with it. Deleting a listener
+/// from the waiting thread waits for its accept to be cancelled, so every connection it
+/// accepted is kept for the next `add_listener`, or for `take_accepted`. Those still held
+/// when the poller is dropped are closed.
+#[cfg(feature = "io-uring")]
+pub struct IoUring {
+    ring: io_uring::IoUring,
+    registrations: Mutex<UringRegistrations>,
+    /// Held for the whole of `wait`, the only user of the submission and completion queues
+    queues: Mutex<()>,
+    /// Interrupts a `wait` in progress to submit the queued entries
+    waker: Waker
+}
+
+#[cfg(feature = "io-uring")]
+struct UringRegistrations {
+    by_fd: HashMap<RawFd, UringRegistration>,
+    /// Connections accepted on each listener, or the errors accepting failed with, until
+    /// taken with `accept` or `take_accepted`. Kept while the listener is deleted.
+    accepted: HashMap<RawFd, VecDeque<Result<RawFd, i32>>>,
+    /// Connections received on and sent to, see `add_stream`
+    streams: HashMap<RawFd, UringStream>,
+    /// Buffers still being sent on connections since deleted, by the send's `user_data`
+    retired: HashMap<u64, Vec<u8>>,
+    /// Buffers connections are received into, None if the kernel can not provide them
+    buffers: Option<BufRing>,
+    /// Events reaped outside of `wait`, or due to a `modify`, reported by the next one
+    pending: Vec<libc::epoll_event>,
+    /// Entries for the waiting thread to submit
+    queued: Vec<io_uring::squeue::Entry>,
+    /// Source of `UringRegistration::user_data`
+    serial: u32,
+    /// Whether a poll on the waker is in flight
+    waker_armed: bool,
+    /// Cleared once the kernel rejected a multishot accept
+    multishot_accept: bool,
+    /// Cleared once the kernel rejected a multishot recv
+    multishot_recv: bool
+}
+
+#[cfg(feature = "io-uring")]
+#[derive(Clone, Copy)]
+struct UringRegistration {
+    events: i32,
+    token: u64,
+    /// `user_data` of the request in flight for the fd, 0 while disarmed. The serial it was
+    /// submitted with is in the high half and the fd in the low, so a completion for a
+    /// request that was since replaced is told apart from the current one.
+    user_data: u64,
+    /// Whether the fd is a listener accepted on, rather than polled
+    accept: bool,
+    /// Whether the fd is a connection received on, rather than polled. Its recv stays in
+    /// flight after an event is reported, so `armed` tells whether the next one may be.
+    stream: bool,
+    armed: bool
+}
+
+/// What was received on a connection and what is left to send on it.
+#[cfg(feature = "io-uring")]
+#[derive(Default)]
+struct UringStream {
+    /// Received since `recv` last took it
+    received: Vec<u8>,
+    /// Set once nothing more will be received, to 0 if the peer closed the connection, or
+    /// to the error receiving or sending failed with
+    ended: Option<i32>,
+    /// Buffers queued to be sent, the first one sent up to `sent`
+    unsent: VecDeque<Vec<u8>>,
+    sent: usize,
+    /// `user_data` of the send in flight, 0 if none is
+    sending: u64
+}
+
+/// Buffers provided to the kernel for recvs to pick from, registered as `BUF_GROUP`.
+#[cfg(feature = "io-uring")]
+struct BufRing {
+    /// `BUF_COUNT` entries shared with the kernel, page aligned
+    ring: *mut io_uring::types::BufRingEntry,
+    layout: Layout,
+    /// `BUF_COUNT` buffers of `BUF_SIZE` bytes each
+    buffers: Vec<u8>,
+    /// Entries provided so far, published to the kernel through the ring's tail
+    tail: u16
+}
+
+// Only touched under `IoUring::registrations`
+#[cfg(feature = "io-uring")]
+unsafe impl Send for BufRing {}
+
+/// `user_data` of the `IORING_OP_POLL_REMOVE` and `IORING_OP_ASYNC_CANCEL` entries, whose
+/// completions are ignored.
+#[cfg(feature = "io-uring")]
+const REMOVE_DATA: u64 = 0;
+
+/// `user_data` of the poll on the waker.
+#[cfg(feature = "io-uring")]
+const WAKER_DATA: u64 = 1;
+
+/// Set in the `user_data` of accepts, so the connections accepted by one that was since
+/// replaced or cancelled are still kept.
+#[cfg(feature = "io-uring")]
+const ACCEPT_DATA: u64 = 1 << 63;
+
+/// Set in the `user_data` of recvs, so the buffer one picked is handed back even after its
+/// connection was deleted.
+#[cfg(feature = "io-uring")]
+const RECV_DATA: u64 = 1 << 62;
+
+/// Set in the `user_data` of sends, which are tracked by `UringStream` rather than by
+/// `UringRegistration`.
+#[cfg(feature = "io-uring")]
+const SEND_DATA: u64 = 1 << 61;
+
+/// Buffer group of `BufRing`, the only one of each ring.
+#[cfg(feature = "io-uring")]
+const BUF_GROUP: u16 = 0;
+
+/// Buffers in each `BufRing`, a power of two.
+#[cfg(feature = "io-uring")]
+const BUF_COUNT: usize = 256;
+
+/// Size of each buffer in a `BufRing`.
+#[cfg(feature = "io-uring")]
+const BUF_SIZE: usize = 4096;
+
+/// Submission queue size of each ring. The completion queue is twice as big, and the kernel
+/// holds on to completions that overflow it.
+#[cfg(feature = "io-uring")]
+const URING_ENTRIES: u32 = 1024;
+
+#[cfg(feature = "io-uring")]
+impl IoUring {
+    /// Fails with `ENOSYS` on kernels older than 5.11, which can not wait with a timeout
+    /// or may drop completions.
+    pub fn new() -> Result<IoUring, Error> {
+        let ring = io_uring::IoUring::new(URING_ENTRIES)?;
+        if !ring.params().is_feature_ext_arg() || !ring.params().is_feature_nodrop() {
+            return Err(Error::from_raw_os_error(libc::ENOSYS));
+        }
+
+        let buffers = match BufRing::new(&ring) {
+            Ok(b) => Some(b),
+            Err(err) => {
+                debug!("Provided buffers unsupported, polling connections: {}", err);
+                None
+            }
+        };
+
+        debug!("io_uring instance created");
+
+        Ok(IoUring {
+            ring: ring,
+            registrations: Mutex::new(UringRegistrations {
+                by_fd: HashMap::new(),
+                accepted: HashMap::new(),
+                streams: HashMap::new(),
+                retired: HashMap::new(),
+                buffers: buffers,
+                pending: Vec::new(),
+                queued: Vec::new(),
+                serial: 0,
+                waker_armed: false,
+                multishot_accept: true,
+                multishot_recv: true
+            }),
+            queues: Mutex::new(()),
+            waker: Waker::new()?
+        })
+    }
+
+    /// Registers `fd`, accepting on it rather than polling it if `accept` is set and the
+    /// kernel supports it. Re-registering an fd keeps it accepted on or polled as it was.
+    fn register(&self,
+                fd: RawFd,
+                events: i32,
+                token: u64,
+                replace: bool,
+                accept: bool)
+                -> Result<(), Error>
+    {
+        { // Mutex lock
+            let mut registrations = match self.registrations.lock() {
+                Ok(g) => g,
+                Err(p) => p.into_inner()
+            };
+            let previous = registrations.by_fd.get(&fd).copied();
+            if let Some(r) = previous.filter(|r| r.stream && replace) {
+                // Its recv stays in flight, only what is reported changes
+                registrations.by_fd.insert(fd, UringRegistration {
+                    events: events,
+                    token: token,
+                    armed: true,
+                    ..r
+                });
+                match stream_event(&mut registrations, fd) {
+                    Some(event) => registrations.pending.push(event),
+                    None => return Ok(())
+                }
+            } else {
+                match previous {
+                    Some(r) if r.user_data != 0 => {
+                        registrations.queued.push(remove_entry(r.user_data));
+                    }
+                    Some(_) => {}
+                    None if replace => return Err(Error::from_raw_os_error(libc::ENOENT)),
+                    None => {}
+                }
+                remove_stream(&mut registrations, fd);
+
+                let accept = match previous {
+                    Some(r) if replace => r.accept,
+                    _ => accept && registrations.multishot_accept
+                };
+                let tag = if accept { ACCEPT_DATA } else { 0 };
+                let user_data = next_user_data(&mut registrations, fd, tag);
+                let entry = if accept {
+                    accept_entry(fd, user_data)
+                } else {
+                    poll_entry(fd, events, user_data)
+                };
+                registrations.queued.push(entry);
+                registrations.by_fd.insert(fd, UringRegistration {
+                    events: events,
+                    token: token,
+                    user_data: user_data,
+                    accept: accept,
+                    stream: false,
+                    armed: false
+                });
+            }
+        } // Mutex unlock
+
+        self.waker.wake();
+
+        Ok(())
+    }
+
+    /// Registers the connection `fd` to be received on, see `add_stream`. Returns false,
+    /// leaving it unregistered, if the kernel can not provide buffers.
+    fn register_stream(&self, fd: RawFd, events: i32, token: u64) -> Result<bool, Error> {
+        { // Mutex lock
+            let mut registrations = match self.registrations.lock() {
+                Ok(g) => g,
+                Err(p) => p.into_inner()
+            };
+            if registrations.buffers.is_none() {
+                return Ok(false);
+            }
+
+            if let Some(r) = registrations.by_fd.get(&fd).copied() {
+                if r.user_data != 0 {
+                    registrations.queued.push(remove_entry(r.user_data));
+                }
+                remove_stream(&mut registrations, fd);
+            }
+
+            let user_data = next_user_data(&mut registrations, fd, RECV_DATA);
+            let entry = recv_entry(fd, user_data, registrations.multishot_recv);
+            registrations.queued.push(entry);
+            registrations.by_fd.insert(fd, UringRegistration {
+                events: events,
+                token: token,
+                user_data: user_data,
+                accept: false,
+                stream: true,
+                armed: true
+            });
+            registrations.streams.insert(fd, UringStream::default());
+        } // Mutex unlock
+
+        self.waker.wake();
+
+        Ok(true)
+    }
+
+    fn deregister(&self, fd: RawFd) -> Result<(), Error> {
+        let registration = { // Mutex lock
+            let mut registrations = match self.registrations.lock() {
+                Ok(g) => g,
+                Err(p) => p.into_inner()
+            };
+            let registration = match registrations.by_fd.remove(&fd) {
+                Some(r) => r,
+                None => return Err(Error::from_raw_os_error(libc::ENOENT))
+            };
+            remove_stream(&mut registrations, fd);
+            if registration.user_data == 0 {
+                return Ok(());
+            }
+            registrations.queued.push(remove_entry(registration.user_data));
+            registration
+        }; // Mutex unlock
+
+        if registration.accept {
+            if let Err(err) = self.cancel_accept(registration.user_data) {
+                error!("Cancelling io_uring accept on fd {}: {}", fd, err);
+            }
+        }
+
+        self.waker.wake();
+
+        Ok(())
+    }
+
+    /// Submits the queued entries, cancelling the accept submitted with `user_data` among
+    /// them, and reaps completions until its last one. Whatever else completes meanwhile is
+    /// reported by the next `wait`.
+    ///
+    /// Only done if no thread is in `wait`, as a listener is deleted by the thread waiting on
+    /// it. Otherwise that thread submits the cancel, and the connections accepted until then
+    /// are still kept, just not by the time `deregister` returns.
+    fn cancel_accept(&self, user_data: u64) -> Result<(), Error> {
+        let _queues = match self.queues.try_lock() {
+            Ok(g) => g,
+            Err(TryLockError::Poisoned(p)) => p.into_inner(),
+            Err(TryLockError::WouldBlock) => return Ok(())
+        };
+
+        let queued = { // Mutex lock
+            let mut registrations = match self.registrations.lock() {
+                Ok(g) => g,
+                Err(p) => p.into_inner()
+            };
+            registrations.queued.split_off(0)
+        }; // Mutex unlock
+        self.push(&queued[..])?;
+
+        // The last completion follows the cancel right away, this only bounds a kernel that
+        // would not post it
+        let deadline = Instant::now() + Duration::from_secs(1);
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left == Duration::from_secs(0) {
+                warn!("Gave up on cancelling an io_uring accept");
+                return Ok(());
+            }
+            let ts = io_uring::types::Timespec::from(left);
+            let args = io_uring::types::SubmitArgs::new().timespec(&ts);
+            if let Err(err) = self.ring.submitter().submit_with_args(1, &args) {
+                let code = err.raw_os_error();
+                if code != Some(libc::ETIME) && code != Some(libc::EBUSY) {
+                    return Err(err);
+                }
+            }
+
+            let mut registrations = match self.registrations.lock() {
+                Ok(g) => g,
+                Err(p) => p.into_inner()
+            };
+            let mut cancelled = false;
+            let mut cq = unsafe { self.ring.completion_shared() };
+            for cqe in &mut cq {
+                if cqe.user_data() == user_data && !io_uring::cqueue::more(cqe.flags()) {
+                    cancelled = true;
+                }
+                if let Some(event) = self.complete(&mut registrations, &cqe) {
+                    registrations.pending.push(event);
+                }
+            }
+            cq.sync();
+            if cancelled {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Handles a completion, returning the event to report for it, if any.
+    fn complete(&self,
+                registrations: &mut UringRegistrations,
+                cqe: &io_uring::cqueue::Entry)
+                -> Option<libc::epoll_event>
+    {
+        let user_data = cqe.user_data();
+        if user_data == REMOVE_DATA {
+            return None;
+        }
+        if user_data == WAKER_DATA {
+            self.waker.reset();
+            registrations.waker_armed = false;
+            return None;
+        }
+
+        let result = cqe.result();
+        let fd = (user_data & 0xffff_ffff) as RawFd;
+        if user_data & RECV_DATA != 0 {
+            return complete_recv(registrations, fd, user_data, result, cqe.flags());
+        }
+        if user_data & SEND_DATA != 0 {
+            return complete_send(registrations, fd, user_data, result);
+        }
+
+        let registration = match registrations.by_fd.get(&fd) {
+            Some(r) if r.user_data == user_data => *r,
+            // A connection accepted by an accept since replaced or cancelled is kept for the
+            // listener, unless its fd was since reused for something else
+            Some(r) if user_data & ACCEPT_DATA != 0 && result >= 0 && !r.accept => {
+                close_accepted(result);
+                return None;
+            }
+            Some(r) if user_data & ACCEPT_DATA != 0 && result >= 0 => {
+                return queue_accepted(registrations, fd, r.token, Ok(result));
+            }
+            None if user_data & ACCEPT_DATA != 0 && result >= 0 => {
+                registrations.accepted.entry(fd).or_default().push_back(Ok(result));
+                return None;
+            }
+            _ => return None
+        };
+
+        if registration.accept {
+            if result == -libc::EINVAL {
+                debug!("Multishot accept unsupported, polling listener fd {}", fd);
+                registrations.multishot_accept = false;
+                let user_data = next_user_data(registrations, fd, 0);
+                registrations.queued.push(poll_entry(fd, registration.events, user_data));
+                if let Some(r) = registrations.by_fd.get_mut(&fd) {
+                    r.user_data = user_data;
+                    r.accept = false;
+                }
+                return None;
+            }
+
+            // Ended by an error or an overflowing completion queue
+            if !io_uring::cqueue::more(cqe.flags()) {
+                let user_data = next_user_data(registrations, fd, ACCEPT_DATA);
+                registrations.queued.push(accept_entry(fd, user_data));
+                if let Some(r) = registrations.by_fd.get_mut(&fd) {
+                    r.user_data = user_data;
+                }
+            }
+
+            let accepted = if result < 0 { Err(-result) } else { Ok(result) };
+            return queue_accepted(registrations, fd, registration.token, accepted);
+        }
+
+        if result < 0 {
+            trace!("Dropping fd {} from io_uring: {}", fd, Error::from_raw_os_error(-result));
+            registrations.by_fd.remove(&fd);
+            return None;
+        }
+
+        let user_data = if registration.events & libc::EPOLLONESHOT != 0 {
+            0
+        } else {
+            // Submitted on the next time around
+            let user_data = next_user_data(registrations, fd, 0);
+            registrations.queued.push(poll_entry(fd, registration.events, user_data));
+            user_data
+        };
+        if let Some(r) = registrations.by_fd.get_mut(&fd) {
+            r.user_data = user_data;
+        }
+
+        Some(libc::epoll_event {
+            events: from_poll_events(result as i16),
+            u64: registration.token
+        })
+    }
+
+    /// Pushes `entries` to the submission queue, submitting what is in it whenever it fills.
+    /// Only called from `wait`.
+    fn push(&self, entries: &[io_uring::squeue::Entry]) -> Result<(), Error> {
+        for entry in entries.iter() {
+            loop {
+                let pushed = unsafe {
+                    let mut sq = self.ring.submission_shared();
+                    sq.push(entry).is_ok()
+                };
+                if pushed {
+                    break;
+                }
+                self.ring.submit()?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "io-uring")]
+impl Poller for IoUring {
+    fn add(&self, fd: RawFd, events: i32, token: u64) -> Result<(), Error> {
+        self.register(fd, events, token, false, false)
+    }
+
+    fn modify(&self, fd: RawFd, events: i32, token: u64) -> Result<(), Error> {
+        self.register(fd, events, token, true, false)
+    }
+
+    fn delete(&self, fd: RawFd) -> Result<(), Error> {
+        self.deregister(fd)
+    }
+
+    fn closing(&self, fd: RawFd) {
+        // Never registered, or already deleted
+        let _ = self.deregister(fd);
+    }
+
+    fn add_listener(&self, fd: RawFd, events: i32, token: u64) -> Result<(), Error> {
+        self.register(fd, events, token, false, true)
+    }
+
+    fn accept(&self, fd: RawFd) -> Option<Result<RawFd, Error>> {
+        let mut registrations = match self.registrations.lock() {
+            Ok(g) => g,
+            Err(p) => p.into_inner()
+        };
+        match registrations.by_fd.get(&fd) {
+            Some(r) if r.accept => {}
+            _ => return None
+        }
+
+        let accepted = registrations.accepted.get_mut(&fd).and_then(|a| a.pop_front());
+        match accepted {
+            Some(Ok(conn_fd)) => Some(Ok(conn_fd)),
+            Some(Err(code)) => Some(Err(Error::from_raw_os_error(code))),
+            None => Some(Err(Error::from_raw_os_error(libc::EAGAIN)))
+        }
+    }
+
+    fn take_accepted(&self, fd: RawFd) -> Vec<RawFd> {
+        let mut registrations = match self.registrations.lock() {
+            Ok(g) => g,
+            Err(p) => p.into_inner()
+        };
+        if registrations.by_fd.contains_key(&fd) {
+            return Vec::new();
+        }
+
+        let accepted = registrations.accepted.remove(&fd).unwrap_or_default();
+        accepted.into_iter().filter_map(|a| a.ok()).collect()
+    }
+
+    fn add_stream(&self, fd: RawFd, events: i32, token: u64) -> Result<bool, Error> {
+        if self.register_stream(fd, events, token)? {
+            return Ok(true);
+        }
+
+        self.add(fd, events, token).map(|_| false)
+    }
+
+    fn recv(&self, fd: RawFd) -> Result<Vec<u8>, Error> {
+        let mut registrations = match self.registrations.lock() {
+            Ok(g) => g,
+            Err(p) => p.into_inner()
+        };
+        let stream = match registrations.streams.get_mut(&fd) {
+            Some(s) => s,
+            None => return Err(Error::from_raw_os_error(libc::EBADF))
+        };
+        if !stream.received.is_empty() {
+            return Ok(mem::take(&mut stream.received));
+        }
+
+        match stream.ended {
+            Some(0) => Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed by peer")),
+            Some(code) => Err(Error::from_raw_os_error(code)),
+            None => Err(Error::from_raw_os_error(libc::EAGAIN))
+        }
+    }
+
+    fn send(&self, fd: RawFd, buf: Vec<u8>) -> Result<(), Error> {
+        { // Mutex lock
+            let mut registrations = match self.registrations.lock() {
+                Ok(g) => g,
+                Err(p) => p.into_inner()
+            };
+            match registrations.streams.get_mut(&fd) {
+                Some(s) => match s.ended {
+                    Some(code) if code != 0 => return Err(Error::from_raw_os_error(code)),
+                    _ if buf.is_empty() => return Ok(()),
+                    _ => s.unsent.push_back(buf)
+                },
+                None => return Err(Error::from_raw_os_error(libc::EBADF))
+            }
+            send_next(&mut registrations, fd);
+        } // Mutex unlock
+
+        self.waker.wake();
+
+        Ok(())
+    }
+
+    fn sending(&self, fd: RawFd) -> bool {
+        let registrations = match self.registrations.lock() {
+            Ok(g) => g,
+            Err(p) => p.into_inner()
+        };
+        match registrations.streams.get(&fd) {
+            Some(s) => !s.unsent.is_empty(),
+            None => false
+        }
+    }
+
+    fn wait(&self, buf: &mut [libc::epoll_event], timeout: i32) -> Result<usize, Error> {
+        let deadline = if timeout < 0 {
+            None
+        } else {
+            Some(Instant::now() + Duration::from_millis(timeout as u64))
+        };
+
+        let _queues = match self.queues.lock() {
+            Ok(g) => g,
+            Err(p) => p.into_inner()
+        };
+
+        // Listeners with connections left from the last time are reported without blocking
+        let mut num_events = 0;
+        { // Mutex lock
+            let registrations = match self.registrations.lock() {
+                Ok(g) => g,
+                Err(p) => p.into_inner()
+            };
+            for (fd, accepted) in registrations.accepted.iter() {
+                if num_events == buf.len() {
+                    break;
+                }
+                if accepted.is_empty() {
+                    continue;
+                }
+                if let Some(r) = registrations.by_fd.get(fd).filter(|r| r.accept) {
+                    buf[num_events] = libc::epoll_event {
+                        events: libc::EPOLLIN as u32,
+                        u64: r.token
+                    };
+                    num_events += 1;
+                }
+            }
+        } // Mutex unlock
+
+        loop {
+            let queued = { // Mutex lock
+                let mut registrations = match self.registrations.lock() {
+                    Ok(g) => g,
+                    Err(p) => p.into_inner()
+                };
+                // Reaped while deleting a listener, or due to a modify
+                let num_pending = cmp::min(registrations.pending.len(), buf.len() - num_events);
+                for event in registrations.pending.drain(..num_pending) {
+                    buf[num_events] = event;
+                    num_events += 1;
+                }
+
+                if !registrations.waker_armed {
+                    registrations.waker_armed = true;
+                    registrations.queued.push(waker_entry(self.waker.fd));
+                }
+                registrations.queued.split_off(0)
+            }; // Mutex unlock
+            self.push(&queued[..])?;
+
+            let submitted = match deadline {
+                _ if num_events > 0 => self.ring.submit(),
+                Some(d) => {
+                    let left = d.saturating_duration_since(Instant::now());
+                    let ts = io_uring::types::Timespec::from(left);
+                    let args = io_uring::types::SubmitArgs::new().timespec(&ts);
+                    self.ring.submitter().submit_with_args(1, &args)
+                }
+                None => self.ring.submit_and_wait(1)
+            };
+            if let Err(err) = submitted {
+                // Timed out, or the completion queue overflowed and has to be read first
+                let code = err.raw_os_error();
+                if code != Some(libc::ETIME) && code != Some(libc::EBUSY) {
+                    return Err(err);
+                }
+            }
+
+            { // Mutex lock
+                let mut registrations = match self.registrations.lock() {
+                    Ok(g) => g,
+                    Err(p) => p.into_inner()
+                };
+
+                let mut cq = unsafe { self.ring.completion_shared() };
+                while num_events < buf.len() {
+                    let cqe = match cq.next() {
+                        Some(cqe) => cqe,
+                        None => break
+                    };
+                    if let Some(event) = self.complete(&mut registrations, &cqe) {
+                        buf[num_events] = event;
+                        num_events += 1;
+                    }
+                }
+                cq.sync();
+            } // Mutex unlock
+
+            // Only woken to submit, or every completion was outdated
+            let expired = match deadline {
+                Some(d) => Instant::now() >= d,
+                None => false
+            };
+            if num_events > 0 || expired {
+                return Ok(num_events);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "io-uring")]
+impl Drop for IoUring {
+    fn drop(&mut self) {
+        // Accepts in flight keep accepting until the ring is closed, and whatever they
+        // accepted last is only found in their completions. Recvs and sends in flight use
+        // buffers freed along with the poller.
+        let mut in_flight = {
+            let registrations = match self.registrations.get_mut() {
+                Ok(r) => r,
+                Err(p) => p.into_inner()
+            };
+            for (_, accepted) in registrations.accepted.drain() {
+                for conn_fd in accepted.into_iter().filter_map(|a| a.ok()) {
+                    close_accepted(conn_fd);
+                }
+            }
+
+            let unsubmitted = registrations.queued.iter()
+                .map(|e| e.get_user_data())
+                .collect::<HashSet<u64>>();
+            let accepts_and_recvs = registrations.by_fd.values()
+                .filter(|r| r.accept || r.stream)
+                .map(|r| r.user_data);
+            let sends = registrations.streams.values()
+                .map(|s| s.sending)
+                .chain(registrations.retired.keys().copied());
+            accepts_and_recvs.chain(sends)
+                .filter(|&u| u != 0 && !unsubmitted.contains(&u))
+                .collect::<HashSet<u64>>()
+        };
+        if in_flight.is_empty() {
+            return;
+        }
+
+        let cancels = in_flight.iter().map(|&u| remove_entry(u)).collect::<Vec<_>>();
+        if let Err(err) = self.push(&cancels[..]) {
+            error!("Cancelling io_uring requests: {}", err);
+            return;
+        }
+
+        // Their last completions follow the cancels right away, this only bounds a kernel
+        // that would not post them
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while !in_flight.is_empty() {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left == Duration::from_secs(0) {
+                warn!("Gave up on {} io_uring requests", in_flight.len());
+                break;
+            }
+            let ts = io_uring::types::Timespec::from(left);
+            let args = io_uring::types::SubmitArgs::new().timespec(&ts);
+            if let Err(err) = self.ring.submitter().submit_with_args(1, &args) {
+                let code = err.raw_os_error();
+                if code != Some(libc::ETIME) && code != Some(libc::EBUSY) {
+                    error!("Waiting on io_uring requests: {}", err);
+                    break;
+                }
+            }
+
+            for cqe in self.ring.completion() {
+                let user_data = cqe.user_data();
+                if user_data & ACCEPT_DATA != 0 && cqe.result() >= 0 {
+                    close_accepted(cqe.result());
+                }
+                if !io_uring::cqueue::more(cqe.flags()) {
+                    in_flight.remove(&user_data);
+                }
+            }
+        }
+    }
+}
+
+/// Closes a connection that was accepted on a listener, but never handed out.
+#[cfg(feature = "io-uring")]
+fn close_accepted(fd: RawFd) {
+    trace!("Closing connection fd {} accepted on a listener, but never handed out", fd);
+    let result = unsafe { libc::close(fd) };
+    if result < 0 {
+        error!("Closing connection fd {}: {}", fd, Error::last_os_error());
+    }
+}
+
+/// Queues a connection accepted on the listener `fd`, or the error accepting failed with,
+/// returning the event to report if it is the first one waiting.
+#[cfg(feature = "io-uring")]
+fn queue_accepted(registrations: &mut UringRegistrations,
+                  fd: RawFd,
+                  token: u64,
+                  accepted: Result<RawFd, i32>)
+                  -> Option<libc::epoll_event>
+{
+    let queue = registrations.accepted.entry(fd).or_default();
+    queue.push_back(accepted);
+    if queue.len() > 1 {
+        return None;
+    }
+
+    Some(libc::epoll_event {
+        events: libc::EPOLLIN as u32,
+        u64: token
+    })
+}
+
+/// Handles the completion of a recv on the connection `fd`, returning the event to report
+/// for it, if any.
+#[cfg(feature = "io-uring")]
+fn complete_recv(registrations: &mut UringRegistrations,
+                 fd: RawFd,
+                 user_data: u64,
+                 result: i32,
+                 flags: u32)
+                 -> Option<libc::epoll_event>
+{
+    // The buffer picked is copied out and handed straight back
+    let mut received = Vec::new();
+    if let Some(bid) = io_uring::cqueue::buffer_select(flags) {
+        if let Some(ref mut buffers) = registrations.buffers {
+            received.extend_from_slice(buffers.get(bid, cmp::max(result, 0) as usize));
+            buffers.provide(bid);
+        }
+    }
+
+    match registrations.by_fd.get(&fd) {
+        Some(r) if r.user_data == user_data => {}
+        // Replaced or deleted since it was submitted
+        _ => return None
+    }
+
+    let more = io_uring::cqueue::more(flags);
+    if result == -libc::EINVAL && registrations.multishot_recv {
+        debug!("Multishot recv unsupported, receiving on fd {} one recv at a time", fd);
+        registrations.multishot_recv = false;
+        rearm_recv(registrations, fd);
+        return None;
+    }
+    if result == -libc::ENOBUFS {
+        // Every buffer was picked before any was handed back
+        rearm_recv(registrations, fd);
+        return None;
+    }
+
+    if result > 0 {
+        if let Some(s) = registrations.streams.get_mut(&fd) {
+            s.received.extend_from_slice(&received[..]);
+        }
+        // Ended by an overflowing completion queue, or a single-shot recv
+        if !more {
+            rearm_recv(registrations, fd);
+        }
+    } else {
+        if let Some(s) = registrations.streams.get_mut(&fd) {
+            s.ended.get_or_insert(-result);
+        }
+        if let Some(r) = registrations.by_fd.get_mut(&fd) {
+            r.user_data = 0;
+        }
+    }
+
+    stream_event(registrations, fd)
+}
+
+/// Handles the completion of a send on the connection `fd`, sending what is left, and
+/// returning the event to report for it, if any.
+#[cfg(feature = "io-uring")]
+fn complete_send(registrations: &mut UringRegistrations,
+                 fd: RawFd,
+                 user_data: u64,
+                 result: i32)
+                 -> Option<libc::epoll_event>
+{
+    // Sent on a connection since deleted
+    if registrations.retired.remove(&user_data).is_some() {
+        return None;
+    }
+
+    match registrations.streams.get_mut(&fd) {
+        Some(s) if s.sending == user_data => {
+            s.sending = 0;
+            if result < 0 {
+                s.ended.get_or_insert(-result);
+                s.unsent.clear();
+                s.sent = 0;
+            } else {
+                s.sent += result as usize;
+                if s.unsent.front().is_some_and(|buf| s.sent >= buf.len()) {
+                    s.unsent.pop_front();
+                    s.sent = 0;
+                }
+            }
+        }
+        _ => return None
+    }
+    send_next(registrations, fd);
+
+    stream_event(registrations, fd)
+}
+
+/// Submits a send of what is left of the first buffer queued on the connection `fd`, unless
+/// one is in flight or nothing is queued.
+#[cfg(feature = "io-uring")]
+fn send_next(registrations: &mut UringRegistrations, fd: RawFd) {
+    let (ptr, len) = match registrations.streams.get(&fd) {
+        Some(s) if s.sending == 0 => match s.unsent.front() {
+            Some(buf) => (buf[s.sent..].as_ptr(), buf.len() - s.sent),
+            None => return
+        },
+        _ => return
+    };
+
+    let user_data = next_user_data(registrations, fd, SEND_DATA);
+    registrations.queued.push(send_entry(fd, ptr, len, user_data));
+    if let Some(s) = registrations.streams.get_mut(&fd) {
+        s.sending = user_data;
+    }
+}
+
+/// Submits a new recv on the connection `fd`, once the last one ended.
+#[cfg(feature = "io-uring")]
+fn rearm_recv(registrations: &mut UringRegistrations, fd: RawFd) {
+    let user_data = next_user_data(registrations, fd, RECV_DATA);
+    let entry = recv_entry(fd, user_data, registrations.multishot_recv);
+    registrations.queued.push(entry);
+    if let Some(r) = registrations.by_fd.get_mut(&fd) {
+        r.user_data = user_data;
+    }
+}
+
+/// Forgets what was received on the connection `fd` and what is left to send on it, keeping
+/// the buffer being sent until the kernel is done with it.
+#[cfg(feature = "io-uring")]
+fn remove_stream(registrations: &mut UringRegistrations, fd: RawFd) {
+    let stream = match registrations.streams.remove(&fd) {
+        Some(s) => s,
+        None => return
+    };
+    if stream.sending == 0 {
+        return;
+    }
+
+    registrations.queued.push(remove_entry(stream.sending));
+    if let Some(buf) = stream.unsent.into_iter().next() {
+        registrations.retired.insert(stream.sending, buf);
+    }
+}
+
+/// Returns the event to report for the connection `fd`, if it is armed and there is
+/// anything to report, disarming it if it is registered with `EPOLLONESHOT`.
+#[cfg(feature = "io-uring")]
+fn stream_event(registrations: &mut UringRegistrations, fd: RawFd) -> Option<libc::epoll_event> {
+    let registration = registrations.by_fd.get_mut(&fd)?;
+    let stream = registrations.streams.get(&fd)?;
+    if !registration.armed {
+        return None;
+    }
+
+    let mut events = 0;
+    let readable = !stream.received.is_empty() || stream.ended.is_some();
+    if registration.events & libc::EPOLLIN != 0 && readable {
+        events |= libc::EPOLLIN;
+    }
+    if registration.events & libc::EPOLLOUT != 0 && stream.sending == 0 {
+        events |= libc::EPOLLOUT;
+    }
+    if events == 0 {
+        return None;
+    }
+
+    if registration.events & libc::EPOLLONESHOT != 0 {
+        registration.armed = false;
+    }
+
+    Some(libc::epoll_event {
+        events: events as u32,
+        u64: registration.token
+    })
+}
+
+/// Returns the `user_data` for a new request on `fd`, marked with `tag`: 0 for a poll, or
+/// one of `ACCEPT_DATA`, `RECV_DATA` and `SEND_DATA`.
+#[cfg(feature = "io-uring")]
+fn next_user_data(registrations: &mut UringRegistrations, fd: RawFd, tag: u64) -> u64 {
+    // Never 0, which marks a disarmed fd, and never REMOVE_DATA. The top bits are the tags'.
+    registrations.serial = registrations.serial.wrapping_add(1) & 0x1fff_ffff;
+    if registrations.serial == 0 {
+        registrations.serial = 1;
+    }
+
+    ((registrations.serial as u64) << 32) | (fd as u32 as u64) | tag
+}
+
+#[cfg(feature = "io-uring")]
+fn poll_entry(fd: RawFd, events: i32, user_data: u64) -> io_uring::squeue::Entry {
+    let poll_events = to_poll_events(events) as u16 as u32;
+    io_uring::opcode::PollAdd::new(io_uring::types::Fd(fd), poll_events)
+        .build()
+        .user_data(user_data)
+}
+
+#[cfg(feature = "io-uring")]
+fn accept_entry(fd: RawFd, user_data: u64) -> io_uring::squeue::Entry {
+    io_uring::opcode::AcceptMulti::new(io_uring::types::Fd(fd))
+        .flags(libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC)
+        .build()
+        .user_data(user_data)
+}
+
+/// Returns a recv picking a buffer from `BUF_GROUP`, which keeps receiving if `multishot`
+/// is set.
+#[cfg(feature = "io-uring")]
+fn recv_entry(fd: RawFd, user_data: u64, multishot: bool) -> io_uring::squeue::Entry {
+    if multishot {
+        return io_uring::opcode::RecvMulti::new(io_uring::types::Fd(fd), BUF_GROUP)
+            .build()
+            .user_data(user_data);
+    }
+
+    io_uring::opcode::Recv::new(io_uring::types::Fd(fd), ptr::null_mut(), BUF_SIZE as u32)
+        .buf_group(BUF_GROUP)
+        .build()
+        .flags(io_uring::squeue::Flags::BUFFER_SELECT)
+        .user_data(user_data)
+}
+
+/// Returns a send of the `len` bytes at `buf`, which must stay put until it completes.
+#[cfg(feature = "io-uring")]
+fn send_entry(fd: RawFd, buf: *const u8, len: usize, user_data: u64) -> io_uring::squeue::Entry {
+    let len = cmp::min(len, u32::MAX as usize) as u32;
+    io_uring::opcode::Send::new(io_uring::types::Fd(fd), buf, len)
+        .flags(libc::MSG_NOSIGNAL)
+        .build()
+        .user_data(user_data)
+}
+
+#[cfg(feature = "io-uring")]
+fn waker_entry(fd: RawFd) -> io_uring::squeue::Entry {
+    io_uring::opcode::PollAdd::new(io_uring::types::Fd(fd), libc::POLLIN as u16 as u32)
+        .build()
+        .user_data(WAKER_DATA)
+}
+
+/// Returns the entry removing the request submitted with `user_data`, a poll or any other.
+#[cfg(feature = "io-uring")]
+fn remove_entry(user_data: u64) -> io_uring::squeue::Entry {
+    if user_data & (ACCEPT_DATA | RECV_DATA | SEND_DATA) != 0 {
+        io_uring::opcode::AsyncCancel::new(user_data)
+            .build()
+            .user_data(REMOVE_DATA)
+    } else {
+        io_uring::opcode::PollRemove::new(user_data)
+            .build()
+            .user_data(REMOVE_DATA)
+    }
+}
+
+#[cfg(feature = "io-uring")]
+impl BufRing {
+    /// Allocates the ring and its buffers, and registers them with `ring`.
+    fn new(ring: &io_uring::IoUring) -> Result<BufRing, Error> {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let size = BUF_COUNT * mem::size_of::<io_uring::types::BufRingEntry>();
+        let layout = match Layout::from_size_align(size, page_size) {
+            Ok(l) => l,
+            Err(_) => return Err(Error::from_raw_os_error(libc::EINVAL))
+        };
+        let entries = unsafe { alloc::alloc_zeroed(layout) };
+        if entries.is_null() {
+            return Err(Error::from_raw_os_error(libc::ENOMEM));
+        }
+
+        let mut buf_ring = BufRing {
+            ring: entries as *mut io_uring::types::BufRingEntry,
+            layout: layout,
+            buffers: vec![0u8; BUF_COUNT * BUF_SIZE],
+            tail: 0
+        };
+        unsafe {
+            ring.submitter().register_buf_ring_with_flags(entries as u64,
+                                                          BUF_COUNT as u16,
+                                                          BUF_GROUP,
+                                                          0)?;
+        }
+        for bid in 0..BUF_COUNT {
+            buf_ring.provide(bid as u16);
+        }
+
+        Ok(buf_ring)
+    }
+
+    /// Hands the buffer `bid` to the kernel, for a recv to pick.
+    fn provide(&mut self, bid: u16) {
+        let addr = self.buffers.as_mut_ptr() as u64 + (bid as usize * BUF_SIZE) as u64;
+        unsafe {
+            let entry = &mut *self.ring.add(self.tail as usize & (BUF_COUNT - 1));
+            entry.set_addr(addr);
+            entry.set_len(BUF_SIZE as u32);
+            entry.set_bid(bid);
+
+            self.tail = self.tail.wrapping_add(1);
+            let tail = io_uring::types::BufRingEntry::tail(self.ring) as *const AtomicU16;
+            (*tail).store(self.tail, Ordering::Release);
+        }
+    }
+
+    /// Returns the `len` bytes a recv received into the buffer `bid`.
+    fn get(&self, bid: u16, len: usize) -> &[u8] {
+        let start = bid as usize * BUF_SIZE;
+        &self.buffers[start..start + cmp::min(len, BUF_SIZE)]
+    }
+}
+
+#[cfg(feature = "io-uring")]
+impl Drop for BufRing {
+    fn drop(&mut self) {
+        unsafe {
+            alloc::dealloc(self.ring as *mut u8, self.layout);
+        }
+    }
+}
+
+/// Converts `EPOLL*` interest flags to `POLL*` ones.
+fn to_poll_events(events: i32) -> i16 {
+    let mut poll_events = 0;
+    if events & libc::EPOLLIN != 0 {
+        poll_events |= libc::POLLIN;
+    }
+    if events & libc::EPOLLOUT != 0 {
+        poll_events |= libc::POLLOUT;
+    }
+    if events & libc::EPOLLRDHUP != 0 {
+        poll_events |= libc::POLLRDHUP;
+    }
+
+    poll_events
+}
+
+/// Converts reported `POLL*` flags to `EPOLL*` ones.
+fn from_poll_events(revents: i16) -> u32 {
+    let mut events = 0;
+    if revents & libc::POLLIN != 0 {
+        events |= libc::EPOLLIN;
+    }
+    if revents & libc::POLLOUT != 0 {
+        events |= libc::EPOLLOUT;
+    }
+    if revents & libc::POLLRDHUP != 0 {
+        events |= libc::EPOLLRDHUP;
+    }
+    if revents & libc::POLLERR != 0 {
+        events |= libc::EPOLLERR;
+    }
+    if revents & libc::POLLHUP != 0 {
+        events |= libc::EPOLLHUP;
+    }
+
+    events as u32
+}
@@ -0,0 +1,266 @@
+// Copyright 2015 Nathan Sizemore <nathanrsizemore@gmail.com>
+//
+// This Source Code Form is subject to the terms of the
+// Mozilla Public License, v. 2.0. If a copy of the MPL was not
+// distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+
+
+use std::collections::VecDeque;
+use std::io::Error;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// The I/O thread pool, shared by every reactor and the `ServerHandle`.
+///
+/// Unlike the `threadpool` crate it keeps its workers' `JoinHandle`s, so `join` returns only
+/// once every worker has exited. Dropping the last clone without joining lets the workers
+/// finish the queued jobs and exit on their own.
+#[derive(Clone)]
+pub struct ThreadPool {
+    handle: Arc<PoolHandle>
+}
+
+/// Closes the pool once the last `ThreadPool` clone lets go of it. The workers only hold
+/// `Shared`, so they do not keep it open.
+struct PoolHandle {
+    shared: Arc<Shared>
+}
+
+struct Shared {
+    state: Mutex<State>,
+    /// Notified when a job is queued, the pool is resized or closed
+    ready: Condvar,
+    /// Every worker spawned, including those that exited since
+    workers: Mutex<Vec<JoinHandle<()>>>
+}
+
+struct State {
+    jobs: VecDeque<Job>,
+    /// Workers wanted, see `set_num_threads`
+    num_threads: usize,
+    /// Workers running, above `num_threads` for a while after shrinking
+    alive: usize,
+    /// Workers running a job
+    active: usize,
+    /// Set once no more jobs are coming, the workers exit once the queue is empty
+    closed: bool
+}
+
+impl ThreadPool {
+    /// Starts a pool of `num_threads` workers.
+    pub fn new(num_threads: usize) -> Result<ThreadPool, Error> {
+        let pool = ThreadPool {
+            handle: Arc::new(PoolHandle {
+                shared: Arc::new(Shared {
+                    state: Mutex::new(State {
+                        jobs: VecDeque::new(),
+                        num_threads: 0,
+                        alive: 0,
+                        active: 0,
+                        closed: false
+                    }),
+                    ready: Condvar::new(),
+                    workers: Mutex::new(Vec::new())
+                })
+            })
+        };
+        pool.set_num_threads(num_threads)?;
+
+        Ok(pool)
+    }
+
+    /// Queues `job` for the next free worker.
+    pub fn execute<F>(&self, job: F) where F: FnOnce() + Send + 'static {
+        let shared = &self.handle.shared;
+        { // Mutex lock
+            let mut state = shared.lock_state();
+            state.jobs.push_back(Box::new(job));
+        } // Mutex unlock
+
+        shared.ready.notify_one();
+    }
+
+    /// Grows or shrinks the pool to `num_threads` workers. Growing spawns the new workers
+    /// straight away, shrinking lets the extra ones finish their current job first.
+    pub fn set_num_threads(&self, num_threads: usize) -> Result<(), Error> {
+        let shared = &self.handle.shared;
+        {
+            // Mutex lock
+            // Held while spawning, so concurrent resizes do not both spawn the difference
+            let mut state = shared.lock_state();
+            state.num_threads = num_threads;
+            while state.alive < state.num_threads {
+                let shared_clone = shared.clone();
+                let worker = thread::Builder::new()
+                    .name("I/O Worker".to_string())
+                    .spawn(move || work(shared_clone))?;
+                state.alive += 1;
+
+                let mut workers = match shared.workers.lock() {
+                    Ok(g) => g,
+                    Err(p) => p.into_inner()
+                };
+                workers.retain(|w| !w.is_finished());
+                workers.push(worker);
+            }
+        } // Mutex unlock
+
+        // Extra workers notice they are no longer wanted
+        shared.ready.notify_all();
+
+        Ok(())
+    }
+
+    /// Returns the number of workers the pool is sized to.
+    pub fn max_count(&self) -> usize {
+        self.handle.shared.lock_state().num_threads
+    }
+
+    /// Returns the number of jobs being run.
+    pub fn active_count(&self) -> usize {
+        self.handle.shared.lock_state().active
+    }
+
+    /// Returns the number of jobs waiting for a free worker.
+    pub fn queued_count(&self) -> usize {
+        self.handle.shared.lock_state().jobs.len()
+    }
+
+    /// Lets the workers finish every queued job, then blocks until all of them have exited.
+    /// No job may be queued afterwards.
+    pub fn join(&self) {
+        let shared = &self.handle.shared;
+        shared.close();
+
+        let workers = { // Mutex lock
+            let mut workers = match shared.workers.lock() {
+                Ok(g) => g,
+                Err(p) => p.into_inner()
+            };
+            workers.split_off(0)
+        }; // Mutex unlock
+
+        for worker in workers.into_iter() {
+            // A panicking job is caught by the worker, which keeps running
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Drop for PoolHandle {
+    fn drop(&mut self) {
+        self.shared.close();
+    }
+}
+
+impl Shared {
+    fn lock_state(&self) -> MutexGuard<'_, State> {
+        match self.state.lock() {
+            Ok(g) => g,
+            Err(p) => p.into_inner()
+        }
+    }
+
+    fn close(&self) {
+        { // Mutex lock
+            let mut state = self.lock_state();
+            state.closed = true;
+        } // Mutex unlock
+
+        self.ready.notify_all();
+    }
+}
+
+/// Runs queued jobs until the pool shrinks below this worker, or is closed and empty.
+fn work(shared: Arc<Shared>) {
+    loop {
+        let job = { // Mutex lock
+            let mut state = shared.lock_state();
+            loop {
+                if state.alive > state.num_threads {
+                    state.alive -= 1;
+                    return;
+                }
+                if let Some(job) = state.jobs.pop_front() {
+                    state.active += 1;
+                    break job;
+                }
+                if state.closed {
+                    state.alive -= 1;
+                    return;
+                }
+
+                state = match shared.ready.wait(state) {
+                    Ok(g) => g,
+                    Err(p) => p.into_inner()
+                };
+            }
+        }; // Mutex unlock
+
+        if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+            error!("An I/O job panicked");
+        }
+
+        { // Mutex lock
+            let mut state = shared.lock_state();
+            state.active -= 1;
+        } // Mutex unlock
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::ThreadPool;
+
+    #[test]
+    fn join_runs_the_queued_jobs_and_waits_for_the_workers() {
+        let pool = ThreadPool::new(2).unwrap();
+        let done = Arc::new(AtomicUsize::new(0));
+        for _ in 0..8 {
+            let done = done.clone();
+            pool.execute(move || {
+                thread::sleep(Duration::from_millis(5));
+                done.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        let workers = pool.handle.shared.workers.lock().unwrap().len();
+        assert_eq!(workers, 2);
+
+        pool.join();
+        assert_eq!(done.load(Ordering::SeqCst), 8);
+        assert_eq!(pool.handle.shared.lock_state().alive, 0);
+        assert!(pool.handle.shared.workers.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn shrinking_keeps_running_jobs_and_survives_a_panic() {
+        let pool = ThreadPool::new(4).unwrap();
+        pool.execute(|| panic!("job panicked on purpose"));
+        pool.set_num_threads(1).unwrap();
+        assert_eq!(pool.max_count(), 1);
+
+        let done = Arc::new(AtomicUsize::new(0));
+        for _ in 0..4 {
+            let done = done.clone();
+            pool.execute(move || {
+                done.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        pool.join();
+        assert_eq!(done.load(Ordering::SeqCst), 4);
+        assert_eq!(pool.active_count(), 0);
+        assert_eq!(pool.queued_count(), 0);
+    }
+}
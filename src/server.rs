@@ -7,23 +7,34 @@
 
 use std::cell::UnsafeCell;
 use std::io::{Error, ErrorKind};
-use std::net::{TcpListener, TcpStream};
-use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
+use std::fs;
+use std::mem;
+use std::net::SocketAddr;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
-use std::{mem, thread};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use std::{cmp, thread};
 
 use errno::errno;
 use libc;
-use simple_slab::Slab;
-use threadpool::ThreadPool;
 
 use crate::types::{
-    Connection, ConnectionSlab, EventHandler, HydrogenSocket, IoEvent, IoPair, IoQueue, MutSlab,
-    NewConnectionSlab,
+    Connection, ConnectionSlab, Drain, EventHandler, EventQueue, HydrogenSocket, IoEvent, IoPair,
+    IoQueue, ListenerFds, ListenerId, MutSlab, NewConnectionSlab, OpenListeners, ReactorPool,
+    Selector, Stats, StopSignal,
 };
-use config::Config;
+use activation;
+use config::{Accept, Backend, Config, ConnectionOptions, Listen, Trigger};
+use error::{HydrogenError, ServerShutdown};
+use signal::{Signal, SignalFd};
+use slab::TokenSlab;
+use socket::{self, Listener};
+use handle::ServerHandle;
+use poller::{self, Poller};
+use pool::ThreadPool;
 
 use super::Handler;
 
@@ -31,101 +42,816 @@ use super::Handler;
 //
 // EPOLLIN          - Data is available in kernel buffer.
 // EPOLLRDHUP       - Peer closed connection.
-// EPOLLET          - Register in EdgeTrigger mode.
 // EPOLLONESHOT     - After an event is pulled out with epoll_wait(2) the associated
 //                    file descriptor is internally disabled and no other events will
 //                    be reported by the epoll interface.
-const DEFAULT_EVENTS: i32 = libc::EPOLLIN | libc::EPOLLRDHUP | libc::EPOLLET | libc::EPOLLONESHOT;
+//
+// Plus EPOLLET, to register in EdgeTrigger mode, unless Config::trigger is Level.
+const DEFAULT_EVENTS: i32 = libc::EPOLLIN | libc::EPOLLRDHUP | libc::EPOLLONESHOT;
+
+// Milliseconds to wait in epoll_wait while draining, so an idle server is noticed quickly
+const DRAIN_WAIT: i32 = 100;
+
+// epoll_event.u64 of the signalfd and the event loop waker. Connections use their TokenSlab
+// token, which never is either.
+const SIGNAL_TOKEN: u64 = u64::MAX;
+const WAKE_TOKEN: u64 = u64::MAX - 1;
+
+// epoll_event.u64 of the first listener accepted on by the event loop, the others counting
+// down from it by index.
+const LISTENER_TOKEN: u64 = u64::MAX - 2;
+
+// epoll_event.u64 of the listener waker. Listeners use their index. The listener thread
+// has an epoll instance of its own, so this can share its value with SIGNAL_TOKEN.
+const WAKER_TOKEN: u64 = u64::MAX;
+
+/// Everything `setup` creates before the server's threads are started.
+struct Resources {
+    stop: Arc<StopSignal>,
+    /// The I/O thread pool, shared by every reactor
+    thread_pool: ThreadPool,
+    /// One per `Config::reactors`
+    reactors: Vec<Reactor>,
+    /// Registered with the first reactor's epoll instance
+    signal_fd: Option<SignalFd>,
+    /// Names from `LISTEN_FDNAMES`, indexed by `ListenerId`
+    listener_names: Vec<Option<String>>,
+}
+
+/// The listeners and epoll instances of one reactor, see `Config::reactors`.
+struct Reactor {
+    listeners: Vec<(ListenerId, Listener)>,
+    /// Unix socket files bound by this server, removed when it stops
+    socket_files: Vec<PathBuf>,
+    epoll: Arc<Selector>,
+    /// The listener thread's own epoll instance, for the listeners and its waker. Only
+    /// created for `Accept::Thread`.
+    listener_epoll: Option<Selector>,
+}
+
+/// What every reactor shares, created once `setup` succeeded.
+struct ServerContext {
+    handler: EventHandler,
+    thread_pool: ThreadPool,
+    listener_fds: ListenerFds,
+    stats: Arc<Stats>,
+    stop: Arc<StopSignal>,
+}
+
+/// What a reactor's event loop works with, down to the functions it calls.
+struct EventLoopContext {
+    /// Index of the reactor
+    r: usize,
+    /// Connections accepted by the listener thread, not yet registered by the event loop
+    new_connections: NewConnectionSlab,
+    connection_slab: ConnectionSlab,
+    io_queue: IoQueue,
+    epoll: Arc<Selector>,
+    thread_pool: ReactorPool,
+    handler: EventHandler,
+    stats: Arc<Stats>,
+    stop: Arc<StopSignal>,
+    max_events: usize,
+    /// Milliseconds, see `Config::epoll_timeout`
+    epoll_timeout: i32,
+    drain_timeout: Duration,
+}
+
+/// Unix socket files bound by `setup`, removed when dropped unless `keep` took them. A
+/// failure part-way through `setup` leaves nothing behind this way.
+struct SocketFiles(Vec<PathBuf>);
+
+impl SocketFiles {
+    /// Takes the files, for the listener that owns them to remove once the server stops.
+    fn keep(mut self) -> Vec<PathBuf> {
+        mem::take(&mut self.0)
+    }
+}
+
+impl Drop for SocketFiles {
+    fn drop(&mut self) {
+        remove_socket_files(&self.0);
+    }
+}
+
+/// Where the event loop's connections are accepted.
+enum Acceptor {
+    /// The listener thread, joined once the event loop stops
+    Thread(JoinHandle<Result<(), HydrogenError>>),
+    /// The event loop itself
+    EventLoop(Accepting),
+}
 
-// Maximum number of events returned from epoll_wait
-const MAX_EVENTS: i32 = 100;
+/// A reactor's listeners, and how connections are accepted from them. Owned by the listener
+/// thread, or by the event loop with `Accept::EventLoop`.
+struct Accepting {
+    listeners: Vec<(ListenerId, Listener)>,
+    /// Unix socket files bound by this server, removed when it stops
+    socket_files: Vec<PathBuf>,
+    listener_fds: ListenerFds,
+    exclusive: bool,
+    /// The most connections accepted from one listener each time it is reported ready
+    batch: usize,
+    connection_options: ConnectionOptions,
+    /// Whether the listeners are in the interest list, they are not while paused
+    registered: bool,
+}
+
+/// Creates each reactor's pollers from `Config::backend`, see `poller::new`.
+pub(crate) type NewPoller = dyn Fn(Backend) -> Result<Box<dyn Poller>, Error>;
 
-// Useful to keep from passing a copy of a RawFd everywhere
-#[allow(non_upper_case_globals)]
-static mut epfd: RawFd = 0 as RawFd;
+/// Starts the server, binding a new listener from `cfg` unless `inherited` listeners are passed.
+pub fn begin(
+    handler: Box<dyn Handler>,
+    cfg: Config,
+    inherited: Vec<Listener>,
+) -> Result<ServerHandle, HydrogenError> {
+    begin_with_pollers(handler, cfg, inherited, &poller::new)
+}
 
-pub fn begin(handler: Box<dyn Handler>, cfg: Config, drop_handler: Option<Arc<AtomicBool>>) {
+/// Starts the server like `begin`, with pollers created by `new_poller` rather than
+/// `poller::new`.
+pub(crate) fn begin_with_pollers(
+    handler: Box<dyn Handler>,
+    cfg: Config,
+    inherited: Vec<Listener>,
+    new_poller: &NewPoller,
+) -> Result<ServerHandle, HydrogenError> {
     info!("Starting server...");
 
     // Wrap handler in something we can share between threads
     let event_handler = EventHandler(Box::into_raw(handler));
 
-    // Create our new connections slab
-    let new_connection_slab = Arc::new(Mutex::new(Slab::<Connection>::with_capacity(10)));
+    let setup_result = unsafe { setup(&cfg, inherited, event_handler.clone(), new_poller) };
+    let Resources {
+        stop,
+        thread_pool,
+        reactors,
+        mut signal_fd,
+        listener_names,
+    } = match setup_result {
+        Ok(resources) => resources,
+        Err(err) => {
+            error!("{}", err);
+
+            // No thread has seen the handler yet
+            let EventHandler(ptr) = event_handler;
+            unsafe {
+                drop(Box::from_raw(ptr));
+            }
+            return Err(err);
+        }
+    };
+
+    // The first reactor has a listener for every ListenerId, the others only for TCP ones
+    let fds = reactors[0]
+        .listeners
+        .iter()
+        .map(|(_, l)| l.as_raw_fd())
+        .collect::<Vec<RawFd>>();
+    let listener_fds = Arc::new(Mutex::new(OpenListeners {
+        fds: fds,
+        handed_off: false,
+    }));
+
+    let server = ServerContext {
+        handler: event_handler,
+        thread_pool: thread_pool,
+        listener_fds: listener_fds,
+        stats: Arc::new(Stats::new()),
+        stop: stop,
+    };
+
+    // Past this point, a failure to spawn leaves earlier threads running
+    // until they notice the stop request. They may still be using the
+    // handler, so it is leaked rather than released.
+    let num_reactors = reactors.len();
+    let mut event_loop_threads = Vec::with_capacity(num_reactors);
+    for (r, reactor) in reactors.into_iter().enumerate() {
+        let event_loop_thread =
+            unsafe { start_reactor(r, num_reactors, reactor, signal_fd.take(), &cfg, &server)? };
+        event_loop_threads.push(event_loop_thread);
+    }
+
+    Ok(ServerHandle::new(
+        server.stop,
+        server.listener_fds,
+        listener_names,
+        server.stats,
+        server.thread_pool,
+        event_loop_threads,
+        server.handler,
+    ))
+}
+
+/// Starts the threads of the `r`th reactor, returning its event loop's handle.
+unsafe fn start_reactor(
+    r: usize,
+    num_reactors: usize,
+    reactor: Reactor,
+    signal_fd: Option<SignalFd>,
+    cfg: &Config,
+    server: &ServerContext,
+) -> Result<JoinHandle<Result<(), HydrogenError>>, HydrogenError> {
+    let event_handler = server.handler.clone();
+    let stop = &server.stop;
+    let Reactor {
+        listeners,
+        socket_files,
+        epoll,
+        listener_epoll,
+    } = reactor;
+
+    // Connections accepted but not yet registered by the event loop
+    let new_connection_slab = Arc::new(Mutex::new(Vec::<Connection>::with_capacity(10)));
 
     // Create our connection slab
+    let pre_allocated = cfg.pre_allocated.div_ceil(num_reactors);
     let mut_slab = MutSlab {
-        inner: UnsafeCell::new(Slab::<Arc<Connection>>::with_capacity(cfg.pre_allocated)),
+        inner: UnsafeCell::new(TokenSlab::<Arc<Connection>>::with_capacity(pre_allocated)),
     };
     let connection_slab = Arc::new(mut_slab);
 
-    // Start the event loop
-    let threads = cfg.max_threads;
+    // Our I/O queue for Connections needing various I/O operations.
+    let arc_io_queue = Arc::new(EventQueue::new(cfg.max_events));
+
+    // Tracks this reactor's jobs on the shared pool
+    let thread_pool = ReactorPool::new(server.thread_pool.clone());
+
+    // Start the I/O Sentinel. It keeps running through a drain, so it
+    // exits once its queue is closed rather than watching the StopSignal.
+    let t_pool_clone = thread_pool.clone();
     let eh_clone = event_handler.clone();
-    let new_connections = new_connection_slab.clone();
-    unsafe {
-        thread::Builder::new()
-            .name("Event Loop".to_string())
-            // !FIXME: Fix this error on simple-slab >= 0.3
-            // `*mut Connection` cannot be sent between threads safely
-            // within `Slab<Connection>`, the trait `Send` is not implemented for `*mut Connection`
-            // the trait `Send` is implemented for `Connection`
-            // required for `Mutex<Slab<Connection>>` to implement `Sync`
-            // required for `Arc<Mutex<Slab<Connection>>>` to implement `Send`rustcE0277
-            // server.rs(68, 14): required by a bound introduced by this call
-            // lib.rs(1, 1): required because it appears within the type `Slab<Connection>`
-            // server.rs(68, 20): required because it's used within this closure
-            // mod.rs(415, 12): required by a bound in `std::thread::Builder::spawn`
-            .spawn(move || {
-                event_loop(
-                    new_connections,
-                    connection_slab,
-                    eh_clone,
-                    threads,
-                    drop_handler,
-                )
-            })
-            .unwrap();
+    let io_queue = arc_io_queue.clone();
+    let epoll_clone = epoll.clone();
+    let stop_clone = stop.clone();
+    let sentinel_thread = match thread::Builder::new()
+        .name(thread_name("I/O Sentinel", r, num_reactors))
+        .spawn(move || io_sentinel(io_queue, t_pool_clone, eh_clone, epoll_clone, stop_clone, r))
+    {
+        Ok(t) => t,
+        Err(err) => return Err(abandon_start(stop, &arc_io_queue, err)),
+    };
+
+    let mut accepting = Accepting {
+        listeners: listeners,
+        socket_files: socket_files,
+        listener_fds: server.listener_fds.clone(),
+        exclusive: cfg.epoll_exclusive,
+        batch: cfg.accept_batch,
+        connection_options: cfg.connection.clone(),
+        registered: false,
+    };
+    let acceptor = match listener_epoll {
+        Some(listener_epoll) => {
+            // Start the listener loop. It has a thread of its own, so it takes everything.
+            accepting.batch = usize::MAX;
+            let eh_clone = event_handler.clone();
+            let new_connections = new_connection_slab.clone();
+            let stats_clone = server.stats.clone();
+            let stop_clone = stop.clone();
+            let listener_thread = match thread::Builder::new()
+                .name(thread_name("Listener Loop", r, num_reactors))
+                .spawn(move || {
+                    listener_loop(
+                        accepting,
+                        listener_epoll,
+                        new_connections,
+                        eh_clone,
+                        stats_clone,
+                        stop_clone,
+                        r,
+                    )
+                }) {
+                Ok(t) => t,
+                Err(err) => return Err(abandon_start(stop, &arc_io_queue, err)),
+            };
+            Acceptor::Thread(listener_thread)
+        }
+        None => Acceptor::EventLoop(accepting),
+    };
+
+    // Start the event loop
+    let context = EventLoopContext {
+        r: r,
+        new_connections: new_connection_slab,
+        connection_slab: connection_slab,
+        io_queue: arc_io_queue.clone(),
+        epoll: epoll,
+        thread_pool: thread_pool,
+        handler: event_handler,
+        stats: server.stats.clone(),
+        stop: stop.clone(),
+        max_events: cfg.max_events,
+        epoll_timeout: clamp_millis(cfg.epoll_timeout),
+        drain_timeout: cfg.drain_timeout,
+    };
+    let event_loop_thread = match thread::Builder::new()
+        .name(thread_name("Event Loop", r, num_reactors))
+        .spawn(move || event_loop(context, signal_fd, sentinel_thread, acceptor))
+    {
+        Ok(t) => t,
+        Err(err) => return Err(abandon_start(stop, &arc_io_queue, err)),
+    };
+
+    Ok(event_loop_thread)
+}
+
+/// Names a thread of the `r`th reactor, numbering it only if there are several.
+fn thread_name(name: &str, r: usize, num_reactors: usize) -> String {
+    if num_reactors == 1 {
+        name.to_string()
+    } else {
+        format!("{} {}", name, r)
     }
+}
 
-    // Start the TcpListener loop
-    let eh_clone = event_handler.clone();
-    let listener_thread = unsafe {
-        thread::Builder::new()
-            .name("TcpListener Loop".to_string())
-            .spawn(move || listener_loop(cfg, new_connection_slab, eh_clone))
-            .unwrap()
+/// Creates everything needed before the reactors' threads can be started: the stop signal,
+/// the listening sockets, the epoll instances, if requested the signalfd, and last the I/O
+/// thread pool.
+unsafe fn setup(
+    cfg: &Config,
+    inherited: Vec<Listener>,
+    handler: EventHandler,
+    new_poller: &NewPoller,
+) -> Result<Resources, HydrogenError> {
+    if let Err(err) = cfg.validate() {
+        return Err(HydrogenError::Config(err));
+    }
+
+    // Flipped by the ServerHandle to stop every thread we start
+    let stop = match StopSignal::new(cfg.reactors) {
+        Ok(s) => Arc::new(s),
+        Err(err) => return Err(HydrogenError::EventFd(err)),
     };
-    let _ = listener_thread.join();
+
+    // Every reactor binds its own socket for each TCP listener, sharing the port
+    let mut bind_cfg = cfg.clone();
+    if cfg.reactors > 1 {
+        bind_cfg.reuse_port = true;
+    }
+
+    // Socket files of inherited or activated listeners belong to whoever bound them
+    let mut socket_files = SocketFiles(Vec::new());
+    let mut bound = true;
+    let mut listener_names = Vec::<Option<String>>::new();
+    let listeners = if !inherited.is_empty() {
+        debug!("Using {} inherited listener(s)", inherited.len());
+        bound = false;
+        inherited
+    } else if cfg.socket_activation {
+        match activation::listeners() {
+            Ok(ref l) if l.is_empty() => {
+                debug!("Not socket activated, binding instead");
+                bind_listeners(&bind_cfg, handler.clone(), &mut socket_files.0)?
+            }
+            Ok(l) => {
+                bound = false;
+                let (listeners, names) = l.into_iter().unzip();
+                listener_names = names;
+                listeners
+            }
+            Err(err) => return Err(HydrogenError::SocketActivation(err)),
+        }
+    } else {
+        bind_listeners(&bind_cfg, handler.clone(), &mut socket_files.0)?
+    };
+    let first = listeners
+        .into_iter()
+        .enumerate()
+        .map(|(x, l)| (ListenerId(x), l))
+        .collect::<Vec<(ListenerId, Listener)>>();
+
+    if !bound && cfg.reactors > 1 {
+        warn!("Listeners not bound by this server are only accepted from by the first reactor");
+    }
+
+    let mut shards = Vec::<Vec<(ListenerId, Listener)>>::with_capacity(cfg.reactors);
+    shards.push(first);
+    for _ in 1..cfg.reactors {
+        let shard = if bound {
+            bind_shard(&shards[0], &bind_cfg, handler.clone())?
+        } else {
+            Vec::new()
+        };
+        shards.push(shard);
+    }
+
+    let events = match cfg.trigger {
+        Trigger::Edge => DEFAULT_EVENTS | libc::EPOLLET,
+        Trigger::Level => DEFAULT_EVENTS,
+    };
+
+    let mut reactors = Vec::<Reactor>::with_capacity(cfg.reactors);
+    for (r, shard) in shards.into_iter().enumerate() {
+        let mut prepared = Vec::<(ListenerId, Listener)>::with_capacity(shard.len());
+        for (id, listener) in shard.into_iter() {
+            prepared.push((id, prepare_listener(listener, handler.clone())?));
+        }
+
+        debug!("Creating {:?} poller...", cfg.backend);
+        let epoll = match new_poller(cfg.backend) {
+            Ok(p) => Arc::new(Selector::new(p, events)),
+            Err(err) => return Err(HydrogenError::EpollCreate(err)),
+        };
+
+        if let Err(err) = epoll.add(stop.event_loop_wakers[r].fd, libc::EPOLLIN, WAKE_TOKEN) {
+            return Err(HydrogenError::EventFd(err));
+        }
+
+        let listener_epoll = match cfg.accept {
+            Accept::Thread => match new_poller(cfg.backend) {
+                Ok(p) => Some(Selector::new(p, 0)),
+                Err(err) => return Err(HydrogenError::EpollCreate(err)),
+            },
+            Accept::EventLoop => None,
+        };
+
+        reactors.push(Reactor {
+            listeners: prepared,
+            socket_files: Vec::new(),
+            epoll: epoll,
+            listener_epoll: listener_epoll,
+        });
+    }
+
+    // Must happen before any thread is spawned, so they inherit the signal mask
+    let signal_fd = if cfg.handle_signals {
+        Some(setup_signal_fd(&reactors[0].epoll)?)
+    } else {
+        None
+    };
+
+    debug!("Creating I/O threadpool with {} threads", cfg.max_threads);
+
+    // ThreadPool with user specified number of threads, shared by every reactor. Its threads
+    // are spawned after the signal mask is set, like every other.
+    let thread_pool = match ThreadPool::new(cfg.max_threads) {
+        Ok(p) => p,
+        Err(err) => return Err(HydrogenError::Spawn(err)),
+    };
+
+    // Nothing can fail past this point, the first reactor removes them from now on
+    reactors[0].socket_files = socket_files.keep();
+
+    Ok(Resources {
+        stop: stop,
+        thread_pool: thread_pool,
+        reactors: reactors,
+        signal_fd: signal_fd,
+        listener_names: listener_names,
+    })
+}
+
+/// Binds another socket for each of `first`'s TCP listeners, on the address it is bound to,
+/// for a reactor other than the first.
+unsafe fn bind_shard(
+    first: &[(ListenerId, Listener)],
+    cfg: &Config,
+    handler: EventHandler,
+) -> Result<Vec<(ListenerId, Listener)>, HydrogenError> {
+    let mut shard = Vec::<(ListenerId, Listener)>::with_capacity(first.len());
+    for &(listener_id, ref listener) in first.iter() {
+        if let Listener::Tcp(ref l) = *listener {
+            // The actual port, should the first reactor have bound port 0
+            let addr = match l.local_addr() {
+                Ok(a) => a,
+                Err(err) => return Err(HydrogenError::Bind(err)),
+            };
+            shard.push((listener_id, bind_listener(&addr, cfg, listener_id, handler.clone())?));
+        }
+    }
+
+    Ok(shard)
+}
+
+/// Creates the signalfd and adds it to the epoll interest list.
+fn setup_signal_fd(epoll: &Selector) -> Result<SignalFd, HydrogenError> {
+    debug!("Creating signalfd...");
+    let signal_fd = match SignalFd::new() {
+        Ok(s) => s,
+        Err(err) => return Err(HydrogenError::SignalFd(err)),
+    };
+
+    if let Err(err) = epoll.add(signal_fd.fd, libc::EPOLLIN, SIGNAL_TOKEN) {
+        return Err(HydrogenError::SignalFd(err));
+    }
+
+    debug!("Signalfd created with fd: {}", signal_fd.fd);
+
+    Ok(signal_fd)
+}
+
+/// Converts `duration` to an epoll timeout in milliseconds.
+fn clamp_millis(duration: Duration) -> i32 {
+    cmp::min(duration.as_millis(), i32::MAX as u128) as i32
+}
+
+/// Stops whatever was started before a thread failed to spawn.
+fn abandon_start(stop: &StopSignal, io_queue: &EventQueue, err: Error) -> HydrogenError {
+    error!("Spawning thread: {}", err);
+
+    stop.request();
+    io_queue.close();
+
+    HydrogenError::Spawn(err)
+}
+
+/// Binds `cfg.addr`, then every extra listener, in `ListenerId` order. The paths of Unix
+/// socket files created are pushed onto `socket_files`.
+unsafe fn bind_listeners(
+    cfg: &Config,
+    handler: EventHandler,
+    socket_files: &mut Vec<PathBuf>,
+) -> Result<Vec<Listener>, HydrogenError> {
+    let EventHandler(handler_ptr) = handler;
+
+    let mut listeners = Vec::<Listener>::with_capacity(1 + cfg.extra_listeners.len());
+    listeners.push(bind_listener(&cfg.addr, cfg, ListenerId(0), handler.clone())?);
+    for (x, listen) in cfg.extra_listeners.iter().enumerate() {
+        let listener_id = ListenerId(x + 1);
+        let before_bind = |fd| (*handler_ptr).on_socket_created(fd, listener_id);
+        let listener = match *listen {
+            Listen::Tcp { ref addr } => bind_listener(addr, cfg, listener_id, handler.clone())?,
+            Listen::Unix { ref path, mode } => {
+                debug!("Starting incoming Unix connection listener on {}...", path.display());
+                let listener = match socket::bind_unix(path, mode, cfg.backlog, before_bind) {
+                    Ok(l) => Listener::Unix(l),
+                    Err(err) => return Err(HydrogenError::Bind(err)),
+                };
+                socket_files.push(path.clone());
+                listener
+            }
+            Listen::AbstractUnix { ref name } => {
+                debug!("Starting incoming Unix connection listener on @{}...", name);
+                match socket::bind_abstract_unix(name, cfg.backlog, before_bind) {
+                    Ok(l) => Listener::Unix(l),
+                    Err(err) => return Err(HydrogenError::Bind(err)),
+                }
+            }
+        };
+        listeners.push(listener);
+    }
+
+    Ok(listeners)
 }
 
-unsafe fn listener_loop(cfg: Config, new_connections: NewConnectionSlab, handler: EventHandler) {
-    debug!("Starting incoming TCP connection listener...");
-    let listener_result = TcpListener::bind((&cfg.addr[..], cfg.port));
-    if listener_result.is_err() {
-        let err = listener_result.unwrap_err();
-        error!("Creating TcpListener: {}", err);
-        panic!("{}", err);
+unsafe fn bind_listener(
+    addr: &SocketAddr,
+    cfg: &Config,
+    listener_id: ListenerId,
+    handler: EventHandler,
+) -> Result<Listener, HydrogenError> {
+    debug!("Starting incoming TCP connection listener on {}...", addr);
+    let EventHandler(handler_ptr) = handler;
+    let before_bind = |fd| (*handler_ptr).on_socket_created(fd, listener_id);
+    match socket::bind_tcp(addr, cfg, before_bind) {
+        Ok(l) => Ok(Listener::Tcp(l)),
+        Err(err) => Err(HydrogenError::Bind(err)),
     }
+}
 
-    let listener = listener_result.unwrap();
-    setup_listener_options(&listener, handler.clone());
+/// Hands the listener to the handler for setup, then readies it for the listener thread.
+unsafe fn prepare_listener(
+    listener: Listener,
+    handler: EventHandler,
+) -> Result<Listener, HydrogenError> {
+    setup_listener_options(&listener, handler);
+
+    // Accepting is driven by poll, so the listener thread can also wait on its waker
+    if let Err(err) = listener.set_nonblocking(true) {
+        return Err(HydrogenError::Bind(err));
+    }
 
-    debug!("Incoming TCP connection listener started");
+    debug!("Incoming connection listener started with fd: {}", listener.as_raw_fd());
+
+    Ok(listener)
+}
 
-    for accept_attempt in listener.incoming() {
-        match accept_attempt {
-            Ok(tcp_stream) => handle_new_connection(tcp_stream, &new_connections, handler.clone()),
-            Err(e) => error!("Accepting connection: {}", e),
+unsafe fn listener_loop(
+    mut accepting: Accepting,
+    listener_epoll: Selector,
+    new_connections: NewConnectionSlab,
+    handler: EventHandler,
+    stats: Arc<Stats>,
+    stop: Arc<StopSignal>,
+    r: usize,
+) -> Result<(), HydrogenError> {
+    // Listeners are registered under their index, the waker under WAKER_TOKEN
+    let waker = &stop.listener_wakers[r];
+    if let Err(err) = listener_epoll.add(waker.fd, libc::EPOLLIN, WAKER_TOKEN) {
+        error!("Adding listener waker to epoll: {}", err);
+        stop.request();
+        return Err(HydrogenError::Accept(err));
+    }
+    let num_listeners = accepting.listeners.len();
+    let mut event_buffer = vec![libc::epoll_event { events: 0, u64: 0 }; num_listeners + 1];
+
+    let mut result = Ok(());
+    'poll: while !stop.is_requested() {
+        // Deregistering while paused leaves connections queued in the backlog
+        let paused = stats.accept_paused.load(Ordering::SeqCst);
+        if paused == accepting.registered {
+            let register = register_listeners(
+                &accepting.listeners,
+                &listener_epoll,
+                !paused,
+                accepting.exclusive,
+                |x| x as u64,
+            );
+            if let Err(err) = register {
+                error!("Registering listeners with epoll: {}", err);
+                result = Err(HydrogenError::Accept(err));
+                break;
+            }
+            accepting.registered = !paused;
+        }
+
+        let num_events = match listener_epoll.wait(&mut event_buffer[..], -1) {
+            Ok(n) => n,
+            Err(err) => {
+                if err.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+
+                error!("During listener epoll_wait: {}", err);
+                result = Err(HydrogenError::Accept(err));
+                break;
+            }
         };
+
+        // Woken to stop, pause or resume, which the top of the loop handles
+        let events = &event_buffer[0..num_events];
+        if events.iter().any(|e| e.u64 == WAKER_TOKEN) {
+            waker.reset();
+            continue;
+        }
+
+        let mut accepted = 0;
+        for event in events.iter() {
+            let (listener_id, ref listener) = accepting.listeners[event.u64 as usize];
+            let accept_result = accept_backlog(
+                || listener_epoll.accept(listener),
+                listener_id,
+                &accepting.connection_options,
+                handler.clone(),
+                &stats,
+                accepting.batch,
+                |connection| queue_new_connection(connection, &new_connections),
+            );
+            match accept_result {
+                Ok(n) => accepted += n,
+                Err(err) => {
+                    result = Err(err);
+                    break 'poll;
+                }
+            }
+        }
+
+        // Have the event loop register them now rather than on its next timeout
+        if accepted > 0 {
+            stop.event_loop_wakers[r].wake();
+        }
     }
 
-    trace!("Dropping TcpListener");
+    // Take the rest of the server down with us if we failed
+    stop.request();
 
-    drop(listener);
+    // Whatever the poller accepted, but never handed out, is drained with the rest
+    if accepting.registered {
+        let result = register_listeners(&accepting.listeners, &listener_epoll, false, false, |x| {
+            x as u64
+        });
+        if let Err(err) = result {
+            error!("Removing listeners from epoll: {}", err);
+        }
+    }
+    take_accepted(&accepting, &listener_epoll, &handler, &stats, |connection| {
+        queue_new_connection(connection, &new_connections)
+    });
+
+    close_listeners(accepting.listeners, &accepting.socket_files, &accepting.listener_fds);
+
+    result
 }
 
-unsafe fn setup_listener_options(listener: &TcpListener, handler: EventHandler) {
+/// Closes the listeners, then removes the Unix socket files unless they were handed off.
+fn close_listeners(
+    listeners: Vec<(ListenerId, Listener)>,
+    socket_files: &[PathBuf],
+    listener_fds: &ListenerFds,
+) {
+    trace!("Closing listeners");
+
+    {
+        // Mutex lock
+        // Keeps ServerHandle::send_listeners from using closed fds
+        let mut open = match listener_fds.lock() {
+            Ok(g) => g,
+            Err(p) => p.into_inner(),
+        };
+        open.fds.clear();
+
+        drop(listeners);
+
+        if !open.handed_off {
+            remove_socket_files(socket_files);
+        }
+    } // Mutex unlock
+}
+
+/// Removes the Unix socket files at `paths`.
+fn remove_socket_files(paths: &[PathBuf]) {
+    for path in paths.iter() {
+        trace!("Removing socket file: {}", path.display());
+        if let Err(err) = fs::remove_file(path) {
+            error!("Removing socket file {}: {}", path.display(), err);
+        }
+    }
+}
+
+/// Adds every listener to, or removes every listener from, an epoll instance, each under the
+/// token `token` returns for its index.
+fn register_listeners<F: Fn(usize) -> u64>(
+    listeners: &[(ListenerId, Listener)],
+    listener_epoll: &Selector,
+    register: bool,
+    exclusive: bool,
+    token: F,
+) -> Result<(), Error> {
+    let events = if exclusive {
+        libc::EPOLLIN | libc::EPOLLEXCLUSIVE
+    } else {
+        libc::EPOLLIN
+    };
+
+    for (x, (_, listener)) in listeners.iter().enumerate() {
+        if register {
+            listener_epoll.add_listener(listener.as_raw_fd(), events, token(x))?;
+        } else {
+            listener_epoll.delete(listener.as_raw_fd())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Accepts with `accept` until the backlog is empty or `limit` connections were accepted,
+/// passing each on to `on_accept`. Returns how many connections were accepted.
+unsafe fn accept_backlog<A, F>(
+    mut accept: A,
+    listener_id: ListenerId,
+    connection_options: &ConnectionOptions,
+    handler: EventHandler,
+    stats: &Stats,
+    limit: usize,
+    mut on_accept: F,
+) -> Result<usize, HydrogenError>
+where
+    A: FnMut() -> Result<RawFd, Error>,
+    F: FnMut(Connection),
+{
+    let mut accepted = 0;
+    while accepted < limit {
+        match accept() {
+            Ok(fd) => {
+                accepted += 1;
+                let new_connection =
+                    handle_new_connection(fd, listener_id, connection_options, handler.clone());
+                if let Some(connection) = new_connection {
+                    stats.accepted.fetch_add(1, Ordering::SeqCst);
+                    on_accept(connection);
+                }
+            }
+            Err(e) => {
+                if e.kind() == ErrorKind::WouldBlock {
+                    return Ok(accepted);
+                }
+                if is_fatal_accept_error(&e) {
+                    error!("Accepting connection: {}", e);
+                    return Err(HydrogenError::Accept(e));
+                }
+
+                // Interrupted, aborted by the peer, or out of fds/memory
+                // for now. Try again on the next wakeup.
+                error!("Accepting connection: {}", e);
+                return Ok(accepted);
+            }
+        };
+    }
+
+    Ok(accepted)
+}
+
+/// Returns true if `accept` failed in a way that retrying will not fix.
+fn is_fatal_accept_error(err: &Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(libc::EBADF | libc::EFAULT | libc::EINVAL | libc::ENOTSOCK)
+    )
+}
+
+unsafe fn setup_listener_options(listener: &Listener, handler: EventHandler) {
     debug!("Setting up listener options");
     let fd = listener.as_raw_fd();
     let EventHandler(handler_ptr) = handler;
@@ -133,178 +859,537 @@ unsafe fn setup_listener_options(listener: &TcpListener, handler: EventHandler)
     (*handler_ptr).on_server_created(fd);
 }
 
+/// Sets up a newly accepted connection, returning None if it was closed instead.
 unsafe fn handle_new_connection(
-    tcp_stream: TcpStream,
-    new_connections: &NewConnectionSlab,
+    fd: RawFd,
+    listener_id: ListenerId,
+    connection_options: &ConnectionOptions,
     handler: EventHandler,
-) {
-    debug!("New connection received");
-    // Take ownership of tcp_stream's underlying file descriptor
-    let fd = tcp_stream.into_raw_fd();
+) -> Option<Connection> {
+    debug!("New connection received on listener: {}", listener_id.0);
+    let EventHandler(handler_ptr) = handler;
+
+    if let Err(err) = socket::set_connection_options(fd, connection_options) {
+        error!("Connection fd {}: {}", fd, err);
+        if !(*handler_ptr).on_connection_options_failed(fd, listener_id, err) {
+            debug!("Closing fd: {}", fd);
+            libc::close(fd);
+            return None;
+        }
+    }
 
     // Execute EventHandler's constructor
-    let EventHandler(handler_ptr) = handler;
-    let arc_stream = (*handler_ptr).on_new_connection(fd);
+    let arc_stream = (*handler_ptr).on_new_connection(fd, listener_id);
 
     // Create a connection structure
-    let connection = Connection {
+    Some(Connection {
         fd: fd,
+        token: 0,
+        listener: listener_id,
         err_mutex: Mutex::new(None),
         tx_mutex: Mutex::new(()),
+        write_backlog: AtomicBool::new(false),
+        closed: Mutex::new(false),
+        completion_io: AtomicBool::new(false),
         stream: arc_stream,
-    };
+    })
+}
 
-    // Insert it into the NewConnectionSlab
-    let mut slab = match (*new_connections).lock() {
+/// Queues a connection accepted on the listener thread for the event loop.
+fn queue_new_connection(connection: Connection, new_connections: &NewConnectionSlab) {
+    let mut slab = match new_connections.lock() {
         Ok(g) => g,
         Err(p) => p.into_inner(),
     };
 
-    (&mut *slab).insert(connection);
+    slab.push(connection);
 }
 
 /// Main event loop
 unsafe fn event_loop(
-    new_connections: NewConnectionSlab,
-    connection_slab: ConnectionSlab,
-    handler: EventHandler,
-    threads: usize,
-    drop_handler: Option<Arc<AtomicBool>>,
-) {
+    context: EventLoopContext,
+    signal_fd: Option<SignalFd>,
+    sentinel_thread: JoinHandle<()>,
+    mut acceptor: Acceptor,
+) -> Result<(), HydrogenError> {
     debug!("Event loop starting...");
-    const MAX_WAIT: i32 = 1000; // Milliseconds
 
-    debug!("Creating epoll instance...");
-    // Attempt to create an epoll instance
-    let result = libc::epoll_create(1);
-    if result < 0 {
-        let err = Error::from_raw_os_error(errno().0 as i32);
-        error!("Creating epoll instance: {}", err);
-        panic!("{}", err);
+    let EventLoopContext {
+        ref new_connections,
+        ref connection_slab,
+        ref io_queue,
+        ref epoll,
+        ref thread_pool,
+        ref handler,
+        ref stats,
+        ref stop,
+        ..
+    } = context;
+
+    // Scratch space for epoll returned events
+    let mut event_buffer = vec![libc::epoll_event { events: 0, u64: 0 }; context.max_events];
+
+    let mut result = Ok(());
+
+    debug!("Starting epoll_wait loop...");
+    while !stop.is_requested() {
+        // Insert any newly received connections into the connection_slab
+        insert_new_connections(new_connections, connection_slab, epoll, stats);
+
+        let inline = match acceptor {
+            Acceptor::EventLoop(ref mut inline) => {
+                if let Err(err) = update_registration(inline, epoll, stats) {
+                    error!("Registering listeners with epoll: {}", err);
+                    result = Err(HydrogenError::Accept(err));
+                    stop.request();
+                    break;
+                }
+                Some(&*inline)
+            }
+            Acceptor::Thread(_) => None,
+        };
+
+        let poll_result = poll_events(&context, &mut event_buffer, context.epoll_timeout, inline);
+        match poll_result {
+            Ok(true) => {
+                handle_signals(&signal_fd, stop, context.drain_timeout, thread_pool, handler)
+            }
+            Ok(false) => {}
+            Err(err) => {
+                error!("{}", err);
+                result = Err(err);
+                stop.request();
+            }
+        }
     }
 
-    // Epoll instance
-    epfd = result;
-    debug!("Epoll instance created with fd: {}", epfd);
+    debug!("Event loop stopping...");
 
-    debug!("Creating I/O threadpool with {} threads", threads);
+    // Stop accepting before anything else
+    match acceptor {
+        Acceptor::Thread(listener_thread) => match listener_thread.join() {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => result = result.and(Err(err)),
+            Err(_) => result = result.and(Err(HydrogenError::Panic)),
+        },
+        Acceptor::EventLoop(inline) => {
+            stop_accepting(inline, connection_slab, epoll, handler, stats)
+        }
+    }
+    insert_new_connections(new_connections, connection_slab, epoll, stats);
 
-    // ThreadPool with user specified number of threads
-    let thread_pool = ThreadPool::new(threads);
+    if result.is_ok() && stop.drain() != Drain::Off {
+        result = drain_connections(&context, &mut event_buffer, &signal_fd);
+    }
 
-    // Our I/O queue for Connections needing various I/O operations.
-    let arc_io_queue = Arc::new(Mutex::new(Vec::<IoPair>::with_capacity(
-        MAX_EVENTS as usize,
-    )));
+    // Nothing new will be handed to the pool past this point
+    io_queue.close();
+    if sentinel_thread.join().is_err() {
+        result = result.and(Err(HydrogenError::Panic));
+    }
+    thread_pool.join();
 
-    // Start the I/O Sentinel
-    let t_pool_clone = thread_pool.clone();
-    let handler_clone = handler.clone();
-    let io_queue = arc_io_queue.clone();
-    thread::Builder::new()
-        .name("I/O Sentinel".to_string())
-        .spawn(move || io_sentinel(io_queue, t_pool_clone, handler_clone))
-        .unwrap();
+    remove_stale_connections(connection_slab, epoll, thread_pool, handler, stats);
+    remove_all_connections(connection_slab, epoll, thread_pool, handler, stats);
+
+    // Wait on the on_connection_removed calls
+    thread_pool.join();
+
+    drop(signal_fd);
+
+    // The epoll instance is closed once any HydrogenSockets still held by the handler let go
+    drop(context);
+
+    debug!("Event loop stopped");
+
+    result
+}
+
+/// Adds the listeners to, or removes them from, the event loop's interest list if
+/// `ServerHandle::pause_accept` or `resume_accept` was called since the last time.
+fn update_registration(
+    inline: &mut Accepting,
+    epoll: &Selector,
+    stats: &Stats,
+) -> Result<(), Error> {
+    let paused = stats.accept_paused.load(Ordering::SeqCst);
+    if paused == inline.registered {
+        register_listeners(&inline.listeners, epoll, !paused, inline.exclusive, listener_token)?;
+        inline.registered = !paused;
+    }
+
+    Ok(())
+}
+
+/// Removes the listeners from the event loop's interest list and closes them. Listeners
+/// handed to another process stay open there, so they would keep being reported otherwise.
+/// Connections the poller accepted, but never handed out, are registered to be drained.
+unsafe fn stop_accepting(
+    inline: Accepting,
+    connection_slab: &ConnectionSlab,
+    epoll: &Selector,
+    handler: &EventHandler,
+    stats: &Stats,
+) {
+    if inline.registered {
+        let result = register_listeners(&inline.listeners, epoll, false, false, listener_token);
+        if let Err(err) = result {
+            error!("Removing listeners from epoll: {}", err);
+        }
+    }
+    take_accepted(&inline, epoll, handler, stats, |connection| {
+        register_connection(connection, connection_slab, epoll, stats)
+    });
+
+    close_listeners(inline.listeners, &inline.socket_files, &inline.listener_fds);
+}
+
+/// Passes the connections `listener_epoll` accepted on the deleted `accepting` listeners, but
+/// never handed out, on to `on_accept`.
+unsafe fn take_accepted<F: FnMut(Connection)>(
+    accepting: &Accepting,
+    listener_epoll: &Selector,
+    handler: &EventHandler,
+    stats: &Stats,
+    mut on_accept: F,
+) {
+    for &(listener_id, ref listener) in accepting.listeners.iter() {
+        let mut accepted = listener_epoll.take_accepted(listener).into_iter();
+        // Never fails, only accepting from a listener can
+        let _ = accept_backlog(
+            || accepted.next().ok_or_else(|| Error::from(ErrorKind::WouldBlock)),
+            listener_id,
+            &accepting.connection_options,
+            handler.clone(),
+            stats,
+            usize::MAX,
+            &mut on_accept,
+        );
+    }
+}
+
+/// Returns the event loop's epoll token for the listener at `index`.
+fn listener_token(index: usize) -> u64 {
+    LISTENER_TOKEN - index as u64
+}
+
+/// Returns the index of the listener `token` belongs to, if it is one of `count` listeners.
+fn listener_index(token: u64, count: usize) -> Option<usize> {
+    if token > LISTENER_TOKEN {
+        return None;
+    }
+
+    let index = LISTENER_TOKEN - token;
+    if index < count as u64 {
+        Some(index as usize)
+    } else {
+        None
+    }
+}
+
+/// Accepts up to `inline.batch` connections from each listener reported ready, registering
+/// them with epoll straight away.
+unsafe fn accept_ready(
+    inline: &Accepting,
+    events: &[libc::epoll_event],
+    connection_slab: &ConnectionSlab,
+    epoll: &Selector,
+    handler: &EventHandler,
+    stats: &Stats,
+) -> Result<(), HydrogenError> {
+    for event in events.iter() {
+        let x = match listener_index(event.u64, inline.listeners.len()) {
+            Some(x) => x,
+            None => continue,
+        };
+
+        let (listener_id, ref listener) = inline.listeners[x];
+        accept_backlog(
+            || epoll.accept(listener),
+            listener_id,
+            &inline.connection_options,
+            handler.clone(),
+            stats,
+            inline.batch,
+            |connection| register_connection(connection, connection_slab, epoll, stats),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Drops connections in an error'd state, waits up to `timeout` milliseconds on epoll and
+/// queues any reported events for the I/O Sentinel. Connections waiting on the `inline`
+/// listeners, if any, are accepted.
+///
+/// Returns true if the signalfd has signals waiting to be read.
+unsafe fn poll_events(
+    context: &EventLoopContext,
+    event_buffer: &mut [libc::epoll_event],
+    timeout: i32,
+    inline: Option<&Accepting>,
+) -> Result<bool, HydrogenError> {
+    let EventLoopContext {
+        ref connection_slab,
+        ref io_queue,
+        ref epoll,
+        ref thread_pool,
+        ref handler,
+        ref stats,
+        ref stop,
+        r,
+        ..
+    } = *context;
+
+    // Remove any connections in an error'd state.
+    remove_stale_connections(connection_slab, epoll, thread_pool, handler, stats);
+
+    // Check for any new events
+    let num_events = match epoll.wait(&mut event_buffer[..], timeout) {
+        Ok(n) => n,
+        Err(err) => {
+            if err.kind() == ErrorKind::Interrupted {
+                // A signal arrived, the caller loops back around
+                return Ok(false);
+            }
+
+            return Err(HydrogenError::EpollWait(err));
+        }
+    };
+
+    let events = &event_buffer[0..num_events];
+
+    // Whatever the waker was woken for is handled on the next time around
+    if events.iter().any(|e| e.u64 == WAKE_TOKEN) {
+        stop.event_loop_wakers[r].reset();
+    }
+
+    let listeners = match inline {
+        Some(inline) => {
+            accept_ready(inline, events, connection_slab, epoll, handler, stats)?;
+            inline.listeners.len()
+        }
+        None => 0,
+    };
+
+    update_io_events(connection_slab, io_queue, events, listeners);
+
+    Ok(events.iter().any(|e| e.u64 == SIGNAL_TOKEN))
+}
+
+/// Reads pending signals and reports each to the handler, running the default action
+/// afterwards unless the handler declines it.
+unsafe fn handle_signals(
+    signal_fd: &Option<SignalFd>,
+    stop: &Arc<StopSignal>,
+    drain_timeout: Duration,
+    thread_pool: &ReactorPool,
+    handler: &EventHandler,
+) {
+    let signal_fd = match *signal_fd {
+        Some(ref s) => s,
+        None => return,
+    };
+
+    for signal in signal_fd.read() {
+        debug!("Received signal: {:?}", signal);
+
+        let handler_clone = (*handler).clone();
+        let stop_clone = stop.clone();
+        thread_pool.execute(move || {
+            let EventHandler(ptr) = handler_clone;
+            if !(*ptr).on_signal(signal) {
+                return;
+            }
+
+            match signal {
+                Signal::Term | Signal::Int => {
+                    // A second one while stopping means stop now
+                    if stop_clone.is_requested() {
+                        stop_clone.request_shutdown();
+                    } else {
+                        stop_clone.request_drain(drain_timeout);
+                    }
+                }
+                Signal::Hup => (*ptr).on_reload(),
+                Signal::Usr1 | Signal::Usr2 => {}
+            }
+        });
+    }
+}
+
+/// Gives every connection a chance to say goodbye, then keeps processing I/O until there is
+/// no queued work and no pending write backlog, or until the drain deadline has passed.
+unsafe fn drain_connections(
+    context: &EventLoopContext,
+    event_buffer: &mut [libc::epoll_event],
+    signal_fd: &Option<SignalFd>,
+) -> Result<(), HydrogenError> {
+    debug!("Draining connections...");
+
+    let EventLoopContext {
+        ref connection_slab,
+        ref io_queue,
+        ref epoll,
+        ref thread_pool,
+        ref handler,
+        ref stop,
+        ..
+    } = *context;
+
+    let slab_ptr = (*connection_slab).inner.get();
+    for arc_connection in (&*slab_ptr).iter() {
+        let handler_clone = (*handler).clone();
+        let hydrogen_socket = HydrogenSocket::new(arc_connection.clone(), epoll.clone());
+        thread_pool.execute(move || {
+            let EventHandler(ptr) = handler_clone;
+            (*ptr).on_connection_draining(hydrogen_socket);
+        });
+    }
+
+    loop {
+        // Re-read every pass, a shutdown() during a drain moves the deadline up
+        let timeout = match stop.drain() {
+            Drain::Off => break,
+            Drain::Until(deadline) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    warn!("Drain deadline reached with work outstanding");
+                    break;
+                }
+
+                cmp::min(clamp_millis(deadline - now).saturating_add(1), DRAIN_WAIT)
+            }
+            Drain::Unbounded => DRAIN_WAIT,
+        };
+        let signaled = poll_events(context, event_buffer, timeout, None)?;
+        if signaled {
+            handle_signals(signal_fd, stop, context.drain_timeout, thread_pool, handler);
+        }
+
+        if is_drained(connection_slab, epoll, io_queue, thread_pool) {
+            debug!("Connections drained");
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns true if there is no queued or running I/O, and no connection is waiting on a
+/// write backlog to clear or on the poller to send.
+unsafe fn is_drained(
+    connection_slab: &ConnectionSlab,
+    epoll: &Selector,
+    arc_io_queue: &IoQueue,
+    thread_pool: &ReactorPool,
+) -> bool {
+    {
+        // Mutex lock
+        // The sentinel hands off to the pool while holding this lock, so an
+        // empty queue here means nothing is stuck in between the two.
+        let io_queue = match arc_io_queue.pairs.lock() {
+            Ok(g) => g,
+            Err(p) => p.into_inner(),
+        };
 
-    // Scratch space for epoll returned events
-    let mut event_buffer = Vec::<libc::epoll_event>::with_capacity(MAX_EVENTS as usize);
-    event_buffer.set_len(MAX_EVENTS as usize);
+        if !io_queue.is_empty() || !thread_pool.is_idle() {
+            return false;
+        }
+    } // Mutex unlock
 
-    debug!("Starting epoll_wait loop...");
-    match drop_handler {
-        Some(drop_handler) => while !drop_handler.load(Ordering::Relaxed) {
-            // Remove any connections in an error'd state.
-            remove_stale_connections(&connection_slab, &thread_pool, &handler);
-
-            // Insert any newly received connections into the connection_slab
-            insert_new_connections(&new_connections, &connection_slab);
-
-            // Check for any new events
-            let result = libc::epoll_wait(epfd, event_buffer.as_mut_ptr(), MAX_EVENTS, MAX_WAIT);
-            if result < 0 {
-                let err = Error::from_raw_os_error(errno().0 as i32);
-                error!("During epoll_wait: {}", err);
-                panic!("{}", err);
-            }
-
-            let num_events = result as usize;
-            update_io_events(
-                &connection_slab,
-                &arc_io_queue,
-                &event_buffer[0..num_events],
-            );
-        },
-        None => loop {
-            // Remove any connections in an error'd state.
-            remove_stale_connections(&connection_slab, &thread_pool, &handler);
-
-            // Insert any newly received connections into the connection_slab
-            insert_new_connections(&new_connections, &connection_slab);
-
-            // Check for any new events
-            let result = libc::epoll_wait(epfd, event_buffer.as_mut_ptr(), MAX_EVENTS, MAX_WAIT);
-            if result < 0 {
-                let err = Error::from_raw_os_error(errno().0 as i32);
-                error!("During epoll_wait: {}", err);
-                panic!("{}", err);
-            }
-
-            let num_events = result as usize;
-            update_io_events(
-                &connection_slab,
-                &arc_io_queue,
-                &event_buffer[0..num_events],
-            );
-        },
-    }
+    let slab_ptr = (*connection_slab).inner.get();
+    !(&*slab_ptr)
+        .iter()
+        .any(|c| {
+            c.write_backlog.load(Ordering::SeqCst)
+                || (c.completion_io.load(Ordering::SeqCst) && epoll.sending(c.fd))
+        })
 }
 
 /// Traverses through the connection slab and creates a list of connections that need dropped,
 /// then traverses that list, drops them, and informs the handler of client drop.
 unsafe fn remove_stale_connections(
     connection_slab: &ConnectionSlab,
-    thread_pool: &ThreadPool,
+    epoll: &Selector,
+    thread_pool: &ReactorPool,
     handler: &EventHandler,
+    stats: &Stats,
 ) {
     let slab_ptr = (*connection_slab).inner.get();
 
-    let mut x: isize = 0;
-    while x < (*slab_ptr).len() as isize {
-        let err_state = {
-            let state = (*slab_ptr)[x as usize].err_mutex.lock().unwrap();
-            if state.is_some() {
-                let err_kind = (*state).as_ref().unwrap().kind();
-                let err_desc = (*state).as_ref().unwrap().to_string();
-                Some(Error::new(err_kind, err_desc))
-            } else {
-                None
-            }
-        };
-
-        err_state.map(|e| {
-            trace!("Found stale connection");
+    let mut stale = Vec::<(u64, Error)>::new();
+    for arc_connection in (&*slab_ptr).iter() {
+        let state = arc_connection.err_mutex.lock().unwrap();
+        if state.is_some() {
+            let err_kind = (*state).as_ref().unwrap().kind();
+            let err_desc = (*state).as_ref().unwrap().to_string();
+            stale.push((arc_connection.token, Error::new(err_kind, err_desc)));
+        }
+    }
 
-            let arc_connection = (*slab_ptr).remove(x as usize);
-            close_connection(&arc_connection);
+    for (token, e) in stale.into_iter() {
+        trace!("Found stale connection");
 
-            let fd = arc_connection.fd;
-            let handler_clone = (*handler).clone();
-            thread_pool.execute(move || {
-                let EventHandler(ptr) = handler_clone;
-                (*ptr).on_connection_removed(fd, e);
-            });
-            x -= 1;
+        let arc_connection = match (*slab_ptr).remove(token) {
+            Some(c) => c,
+            None => continue,
+        };
+        close_connection(epoll, &arc_connection);
+        stats.connections.fetch_sub(1, Ordering::SeqCst);
+
+        let fd = arc_connection.fd;
+        let handler_clone = (*handler).clone();
+        thread_pool.execute(move || {
+            let EventHandler(ptr) = handler_clone;
+            (*ptr).on_connection_removed(fd, e);
         });
+    }
+}
+
+/// Drops every connection left in the slab and informs the handler of each drop.
+unsafe fn remove_all_connections(
+    connection_slab: &ConnectionSlab,
+    epoll: &Selector,
+    thread_pool: &ReactorPool,
+    handler: &EventHandler,
+    stats: &Stats,
+) {
+    let slab_ptr = (*connection_slab).inner.get();
 
-        x += 1;
+    let tokens = (&*slab_ptr).iter().map(|c| c.token).collect::<Vec<u64>>();
+    for token in tokens.into_iter() {
+        let arc_connection = match (*slab_ptr).remove(token) {
+            Some(c) => c,
+            None => continue,
+        };
+        close_connection(epoll, &arc_connection);
+        stats.connections.fetch_sub(1, Ordering::SeqCst);
+
+        let fd = arc_connection.fd;
+        let handler_clone = (*handler).clone();
+        thread_pool.execute(move || {
+            let EventHandler(ptr) = handler_clone;
+            (*ptr).on_connection_removed(fd, ServerShutdown.into_io_error());
+        });
     }
 }
 
 /// Closes the connection's underlying file descriptor
-unsafe fn close_connection(connection: &Arc<Connection>) {
+unsafe fn close_connection(epoll: &Selector, connection: &Arc<Connection>) {
     let fd = (*connection).fd;
     debug!("Closing fd: {}", fd);
 
+    // Mutex lock
+    // Marked closed before the fd number can be reused
+    let mut closed = match connection.closed.lock() {
+        Ok(g) => g,
+        Err(p) => p.into_inner(),
+    };
+    *closed = true;
+
+    epoll.closing(fd);
+
     let result = libc::close(fd);
     if result < 0 {
         let err = Error::from_raw_os_error(errno().0 as i32);
@@ -312,108 +1397,84 @@ unsafe fn close_connection(connection: &Arc<Connection>) {
     }
 }
 
-/// Transfers Connections from the new_connections slab to the "main" connection_slab.
+/// Transfers Connections from the new_connections queue to the "main" connection_slab.
 unsafe fn insert_new_connections(
     new_connections: &NewConnectionSlab,
     connection_slab: &ConnectionSlab,
+    epoll: &Selector,
+    stats: &Stats,
 ) {
-    let mut new_slab = match new_connections.lock() {
-        Ok(g) => g,
-        Err(p) => p.into_inner(),
-    };
-
-    let num_connections = (&mut *new_slab).len();
-    let arc_main_slab = (*connection_slab).inner.get();
-    for _ in 0..num_connections {
-        let connection = (&mut *new_slab).remove(0);
-        let arc_connection = Arc::new(connection);
-        (*arc_main_slab).insert(arc_connection.clone());
-        add_connection_to_epoll(&arc_connection);
-    }
-}
-
-/// Adds a new connection to the epoll interest list.
-unsafe fn add_connection_to_epoll(arc_connection: &Arc<Connection>) {
-    let fd = (*arc_connection).fd;
-    debug!("Adding fd {} to epoll", fd);
-    let result = libc::epoll_ctl(
-        epfd,
-        libc::EPOLL_CTL_ADD,
-        fd,
-        &mut libc::epoll_event {
-            events: DEFAULT_EVENTS as u32,
-            u64: fd as u64,
-        },
-    );
-
-    if result < 0 {
-        let err = Error::from_raw_os_error(errno().0 as i32);
-        error!("Adding fd: {} to epoll:   {}", fd, err);
-
-        let mut err_state = match arc_connection.err_mutex.lock() {
+    let pending = {
+        // Mutex lock
+        // Swapped out so the listener thread is not kept waiting on epoll_ctl
+        let mut new_slab = match new_connections.lock() {
             Ok(g) => g,
             Err(p) => p.into_inner(),
         };
+        if new_slab.is_empty() {
+            return;
+        }
 
-        *err_state = Some(err);
+        mem::take(&mut *new_slab)
+    }; // Mutex unlock
+
+    for connection in pending.into_iter() {
+        register_connection(connection, connection_slab, epoll, stats);
     }
 }
 
-/// Re-arms a connection in the epoll interest list with the event mask.
-unsafe fn rearm_connection_in_epoll(arc_connection: &Arc<Connection>, flags: i32) {
-    let fd = arc_connection.fd;
-    let events = DEFAULT_EVENTS | flags;
-
-    trace!("EPOLL_CTL_MOD   fd: {}    flags: {:#b}", fd, (flags as u32));
-
-    let result = libc::epoll_ctl(
-        epfd,
-        libc::EPOLL_CTL_MOD,
-        fd,
-        &mut libc::epoll_event {
-            events: events as u32,
-            u64: fd as u64,
-        },
-    );
-
-    if result < 0 {
-        let err = Error::from_raw_os_error(errno().0 as i32);
-        error!("EPOLL_CTL_MOD   fd: {}    {}", fd, err);
-
-        let mut err_state = match arc_connection.err_mutex.lock() {
-            Ok(g) => g,
-            Err(p) => p.into_inner(),
-        };
-
-        *err_state = Some(err);
-    }
+/// Inserts a connection into the connection_slab and adds it to the epoll interest list.
+unsafe fn register_connection(
+    mut connection: Connection,
+    connection_slab: &ConnectionSlab,
+    epoll: &Selector,
+    stats: &Stats,
+) {
+    let arc_main_slab = (*connection_slab).inner.get();
+    connection.token = (*arc_main_slab).next_token();
+    let arc_connection = Arc::new(connection);
+    (*arc_main_slab).insert(arc_connection.clone());
+    stats.connections.fetch_add(1, Ordering::SeqCst);
+    epoll.add_connection(&arc_connection);
 }
 
 /// Traverses the ConnectionSlab and updates any connection's state reported changed by epoll.
+/// Events for the first `listeners` listener tokens are skipped.
 unsafe fn update_io_events(
     connection_slab: &ConnectionSlab,
     arc_io_queue: &IoQueue,
     events: &[libc::epoll_event],
+    listeners: usize,
 ) {
     const READ_EVENT: u32 = libc::EPOLLIN as u32;
     const WRITE_EVENT: u32 = libc::EPOLLOUT as u32;
     const CLOSE_EVENT: u32 = (libc::EPOLLRDHUP | libc::EPOLLERR | libc::EPOLLHUP) as u32;
 
+    // Queued in one go, so the I/O Sentinel is woken once per epoll_wait
+    let mut io_pairs = Vec::<IoPair>::with_capacity(events.len());
     for event in events.iter() {
+        if event.u64 == SIGNAL_TOKEN {
+            continue;
+        }
+        if event.u64 == WAKE_TOKEN || listener_index(event.u64, listeners).is_some() {
+            continue;
+        }
+
         // Locate the connection this event is for
-        let fd = event.u64 as RawFd;
+        let token = event.u64;
 
         let flags = event.events;
 
-        trace!("Epoll event for fd: {fd}    flags: {flags:#b}");
-
-        let find_result = find_connection_from_fd(fd, connection_slab);
-        if find_result.is_err() {
-            error!("Unable to find fd {} in ConnectionSlab", fd);
-            continue;
-        }
+        trace!("Epoll event for token: {token:#x}    flags: {flags:#b}");
 
-        let arc_connection = find_result.unwrap();
+        // Reported before its connection was removed, maybe with the fd already reused
+        let arc_connection = match find_connection(token, connection_slab) {
+            Some(c) => c,
+            None => {
+                trace!("Dropping stale event for token: {:#x}", token);
+                continue;
+            }
+        };
 
         // Error/hangup occurred?
         let close_event = (event.events & CLOSE_EVENT) > 0;
@@ -470,83 +1531,69 @@ unsafe fn update_io_events(
         };
 
         trace!("Adding event to queue");
-        {
-            // Mutex lock
-            let mut io_queue = match arc_io_queue.lock() {
-                Ok(g) => g,
-                Err(p) => p.into_inner(),
-            };
-
-            (*io_queue).push(io_pair);
-        } // Mutex unlock
+        io_pairs.push(io_pair);
     }
+
+    arc_io_queue.push(&mut io_pairs);
 }
 
-/// Given a fd and ConnectionSlab, returns the Connection associated with fd.
-unsafe fn find_connection_from_fd(
-    fd: RawFd,
-    connection_slab: &ConnectionSlab,
-) -> Result<Arc<Connection>, ()> {
+/// Given a token and ConnectionSlab, returns the Connection the token was issued to, if it is
+/// still in the slab.
+unsafe fn find_connection(token: u64, connection_slab: &ConnectionSlab) -> Option<Arc<Connection>> {
     let slab_ptr = (*connection_slab).inner.get();
-    for ref arc_connection in (*slab_ptr).iter() {
-        if (*arc_connection).fd == fd {
-            return Ok((*arc_connection).clone());
-        }
-    }
-
-    Err(())
+    (*slab_ptr).get(token).cloned()
 }
 
-unsafe fn io_sentinel(arc_io_queue: IoQueue, thread_pool: ThreadPool, handler: EventHandler) {
+unsafe fn io_sentinel(
+    arc_io_queue: IoQueue,
+    thread_pool: ReactorPool,
+    handler: EventHandler,
+    epoll: Arc<Selector>,
+    stop: Arc<StopSignal>,
+    r: usize,
+) {
     info!("Starting I/O Sentinel");
-    // We want to wake up with the same interval consitency as the epoll_wait loop.
-    // Plus a few ms for hopeful non-interference from mutex contention.
-    let _100ms = 1000000 * 100;
-    let wait_interval = Duration::new(0, _100ms);
 
     loop {
-        thread::sleep(wait_interval);
-
-        let io_queue;
-        {
-            // Mutex lock
-            let mut queue = match arc_io_queue.lock() {
-                Ok(g) => g,
-                Err(p) => p.into_inner(),
-            };
-
-            let empty_queue = Vec::<IoPair>::with_capacity(MAX_EVENTS as usize);
-            io_queue = mem::replace(&mut (*queue), empty_queue);
-        } // Mutex unlock
-
-        if io_queue.len() > 0 {
-            trace!("Processing {} I/O events", io_queue.len());
+        // Mutex lock
+        // Held until everything is handed to the pool, see is_drained
+        let mut io_queue = arc_io_queue.wait();
+        if arc_io_queue.is_closed() {
+            break;
         }
 
-        for ref io_pair in io_queue.iter() {
-            let io_event = io_pair.event.clone();
+        trace!("Processing {} I/O events", io_queue.len());
+
+        for io_pair in io_queue.drain(..) {
+            let io_event = io_pair.event;
             let handler_clone = handler.clone();
-            let arc_connection = io_pair.arc_connection.clone();
+            let arc_connection = io_pair.arc_connection;
+            let epoll_clone = epoll.clone();
+            let stop_clone = stop.clone();
+            // An error'd connection is removed by the event loop, which is woken to do so now
             thread_pool.execute(move || {
                 let mut rearm_events = 0i32;
                 if io_event == IoEvent::WriteAvailable || io_event == IoEvent::ReadWriteAvailable {
                     let flags = handle_write_event(arc_connection.clone());
                     if flags == -1 {
+                        stop_clone.event_loop_wakers[r].wake();
                         return;
                     }
                     rearm_events |= flags;
                 }
                 if io_event == IoEvent::ReadAvailable || io_event == IoEvent::ReadWriteAvailable {
-                    let flags = handle_read_event(arc_connection.clone(), handler_clone);
+                    let flags =
+                        handle_read_event(arc_connection.clone(), handler_clone, &epoll_clone);
                     if flags == -1 {
+                        stop_clone.event_loop_wakers[r].wake();
                         return;
                     }
                     rearm_events |= flags;
                 }
 
-                rearm_connection_in_epoll(&arc_connection, rearm_events);
+                epoll_clone.rearm(&arc_connection, rearm_events);
             });
-        }
+        } // Mutex unlock
     }
 }
 
@@ -569,6 +1616,7 @@ unsafe fn handle_write_event(arc_connection: Arc<Connection>) -> i32 {
         let write_result = (*stream_ptr).send(&empty[..]);
         if write_result.is_ok() {
             debug!("Cleared backlog");
+            arc_connection.write_backlog.store(false, Ordering::SeqCst);
             return 0i32;
         }
 
@@ -592,18 +1640,28 @@ unsafe fn handle_write_event(arc_connection: Arc<Connection>) -> i32 {
     return -1i32;
 }
 
-unsafe fn handle_read_event(arc_connection: Arc<Connection>, handler: EventHandler) -> i32 {
+unsafe fn handle_read_event(
+    arc_connection: Arc<Connection>,
+    handler: EventHandler,
+    epoll: &Arc<Selector>,
+) -> i32 {
     trace!("Handling read event");
     let stream_ptr = arc_connection.stream.get();
 
-    // Attempt recv
-    match (*stream_ptr).recv() {
+    // Attempt recv, or take what the poller received
+    let received = if arc_connection.completion_io.load(Ordering::SeqCst) {
+        epoll
+            .recv(arc_connection.fd)
+            .and_then(|buf| (*stream_ptr).decode(&buf))
+    } else {
+        (*stream_ptr).recv()
+    };
+    match received {
         Ok(mut queue) => {
             trace!("Read {} msgs", queue.len());
             for msg in queue.drain(..) {
                 let EventHandler(ptr) = handler;
-                let hydrogen_socket =
-                    HydrogenSocket::new(arc_connection.clone(), rearm_connection_in_epoll);
+                let hydrogen_socket = HydrogenSocket::new(arc_connection.clone(), epoll.clone());
                 (*ptr).on_data_received(hydrogen_socket, msg);
             }
             return libc::EPOLLIN;
@@ -641,3 +1699,606 @@ unsafe fn handle_read_event(arc_connection: Arc<Connection>, handler: EventHandl
 
     return -1i32;
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::UnsafeCell;
+    use std::collections::VecDeque;
+    use std::env;
+    use std::fs;
+    use std::io::{Error, ErrorKind, Read, Write};
+    use std::mem::ManuallyDrop;
+    use std::net::TcpListener;
+    use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::Path;
+    use std::process;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::{Arc, Condvar, Mutex};
+    use std::time::{Duration, Instant};
+
+    use libc;
+
+    use super::{begin, begin_with_pollers, LISTENER_TOKEN};
+    use config::{Accept, Backend, Config};
+    use error::{is_server_shutdown, HydrogenError};
+    use handle::ServerHandle;
+    use poller::Poller;
+    use socket::Listener;
+    use types::{HydrogenSocket, ListenerId};
+    use {Handler, Stream};
+
+    /// Events and connections for `FakePoller` to report, and what was registered with it.
+    #[derive(Default)]
+    struct Script {
+        /// `(events, token)` pairs `wait` returns
+        events: Mutex<VecDeque<(u32, u64)>>,
+        ready: Condvar,
+        /// Connections `accept` hands out
+        connections: Mutex<VecDeque<RawFd>>,
+        /// `(fd, token)` of every `add`
+        added: Mutex<Vec<(RawFd, u64)>>,
+        /// `(fd, events)` of every `modify`
+        modified: Mutex<Vec<(RawFd, i32)>>,
+    }
+
+    impl Script {
+        fn report(&self, events: i32, token: u64) {
+            self.events.lock().unwrap().push_back((events as u32, token));
+            self.ready.notify_all();
+        }
+
+        /// Waits for `fd` to be added, returning its token.
+        fn token_of(&self, fd: RawFd) -> u64 {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while Instant::now() < deadline {
+                let added = self.added.lock().unwrap();
+                if let Some(&(_, token)) = added.iter().find(|&&(f, _)| f == fd) {
+                    return token;
+                }
+                drop(added);
+                ::std::thread::sleep(Duration::from_millis(1));
+            }
+            panic!("fd {} was never added", fd);
+        }
+
+        /// Has the server accept one end of a socket pair, returning the other end, and the
+        /// fd and token of the connection.
+        fn connect(&self) -> (UnixStream, RawFd, u64) {
+            // Only the fake reports the connection, the real listener never sees it
+            let (client, conn) = UnixStream::pair().unwrap();
+            conn.set_nonblocking(true).unwrap();
+            let conn_fd = conn.into_raw_fd();
+            self.connections.lock().unwrap().push_back(conn_fd);
+            self.report(libc::EPOLLIN, LISTENER_TOKEN);
+
+            (client, conn_fd, self.token_of(conn_fd))
+        }
+
+        /// Waits for `fd` to be re-armed with `events`.
+        fn wait_for_rearm(&self, fd: RawFd, events: i32) {
+            let rearmed = wait_until(|| {
+                let modified = self.modified.lock().unwrap();
+                modified.iter().any(|&(f, e)| f == fd && e & events == events)
+            });
+            assert!(rearmed, "fd {} was never re-armed with {:#x}", fd, events);
+        }
+    }
+
+    /// Polls `done` until it returns true, for up to 5 seconds. Returns what it last returned.
+    fn wait_until<F: Fn() -> bool>(done: F) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done() {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            ::std::thread::sleep(Duration::from_millis(1));
+        }
+        true
+    }
+
+    /// Starts a server accepting on its event loop, with every poller a `FakePoller` run by
+    /// `script`.
+    fn start(handler: Box<dyn Handler>, script: &Arc<Script>) -> ServerHandle {
+        let new_poller = {
+            let script = script.clone();
+            move |_: Backend| -> Result<Box<dyn Poller>, Error> {
+                Ok(Box::new(FakePoller(script.clone())))
+            }
+        };
+        let cfg = Config::builder()
+            .addr("127.0.0.1:0")
+            .accept(Accept::EventLoop)
+            .epoll_timeout(Duration::from_millis(10))
+            .max_threads(1)
+            .build()
+            .unwrap();
+
+        begin_with_pollers(handler, cfg, Vec::new(), &new_poller).unwrap()
+    }
+
+    /// Poller that only reports what its `Script` is told to.
+    struct FakePoller(Arc<Script>);
+
+    impl Poller for FakePoller {
+        fn add(&self, fd: RawFd, _events: i32, token: u64) -> Result<(), Error> {
+            self.0.added.lock().unwrap().push((fd, token));
+            Ok(())
+        }
+
+        fn modify(&self, fd: RawFd, events: i32, _token: u64) -> Result<(), Error> {
+            self.0.modified.lock().unwrap().push((fd, events));
+            Ok(())
+        }
+
+        fn delete(&self, _fd: RawFd) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn wait(&self, buf: &mut [libc::epoll_event], timeout: i32) -> Result<usize, Error> {
+            let mut events = self.0.events.lock().unwrap();
+            if events.is_empty() {
+                let timeout = Duration::from_millis(timeout.max(0) as u64);
+                events = self.0.ready.wait_timeout(events, timeout).unwrap().0;
+            }
+
+            let mut num_events = 0;
+            while num_events < buf.len() {
+                let (events, token) = match events.pop_front() {
+                    Some(e) => e,
+                    None => break,
+                };
+                buf[num_events] = libc::epoll_event { events: events, u64: token };
+                num_events += 1;
+            }
+
+            Ok(num_events)
+        }
+
+        fn accept(&self, _fd: RawFd) -> Option<Result<RawFd, Error>> {
+            match self.0.connections.lock().unwrap().pop_front() {
+                Some(fd) => Some(Ok(fd)),
+                None => Some(Err(Error::from(ErrorKind::WouldBlock))),
+            }
+        }
+    }
+
+    struct Echo {
+        removed: Arc<AtomicUsize>,
+    }
+
+    /// The server closes the fd itself
+    struct Conn(ManuallyDrop<UnixStream>);
+
+    impl AsRawFd for Conn {
+        fn as_raw_fd(&self) -> RawFd {
+            self.0.as_raw_fd()
+        }
+    }
+
+    impl Stream for Conn {
+        fn recv(&mut self) -> Result<Vec<Vec<u8>>, Error> {
+            let mut buf = [0u8; 64];
+            match self.0.read(&mut buf) {
+                Ok(0) => Err(Error::from(ErrorKind::UnexpectedEof)),
+                Ok(n) => Ok(vec![buf[..n].to_vec()]),
+                Err(err) => Err(err),
+            }
+        }
+
+        fn send(&mut self, buf: &[u8]) -> Result<(), Error> {
+            self.0.write_all(buf)
+        }
+
+        fn shutdown(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    impl Handler for Echo {
+        fn on_server_created(&mut self, _fd: RawFd) {}
+
+        // Handler hands streams out as Arc<UnsafeCell<_>>, which is never Sync
+        #[allow(clippy::arc_with_non_send_sync)]
+        fn on_new_connection(&mut self, fd: RawFd, _: ListenerId) -> Arc<UnsafeCell<dyn Stream>> {
+            let stream = unsafe { UnixStream::from_raw_fd(fd) };
+            Arc::new(UnsafeCell::new(Conn(ManuallyDrop::new(stream))))
+        }
+
+        fn on_data_received(&mut self, socket: HydrogenSocket, buf: Vec<u8>) {
+            socket.send(&buf);
+        }
+
+        fn on_connection_removed(&mut self, _fd: RawFd, _err: Error) {
+            self.removed.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Holds the first `on_new_connection` up until opened.
+    #[derive(Default)]
+    struct Gate {
+        entered: AtomicBool,
+        open: Mutex<bool>,
+        opened: Condvar,
+    }
+
+    impl Gate {
+        fn open(&self) {
+            *self.open.lock().unwrap() = true;
+            self.opened.notify_all();
+        }
+    }
+
+    /// Echoes, once `gate` lets the first connection through.
+    struct Gated {
+        echo: Echo,
+        gate: Arc<Gate>,
+    }
+
+    impl Handler for Gated {
+        fn on_server_created(&mut self, fd: RawFd) {
+            self.echo.on_server_created(fd);
+        }
+
+        fn on_new_connection(&mut self, fd: RawFd, id: ListenerId) -> Arc<UnsafeCell<dyn Stream>> {
+            self.gate.entered.store(true, Ordering::SeqCst);
+            let mut open = self.gate.open.lock().unwrap();
+            while !*open {
+                open = self.gate.opened.wait(open).unwrap();
+            }
+            drop(open);
+
+            self.echo.on_new_connection(fd, id)
+        }
+
+        fn on_data_received(&mut self, socket: HydrogenSocket, buf: Vec<u8>) {
+            self.echo.on_data_received(socket, buf);
+        }
+
+        fn on_connection_removed(&mut self, fd: RawFd, err: Error) {
+            self.echo.on_connection_removed(fd, err);
+        }
+    }
+
+    /// Says goodbye to every connection drained, and keeps why each was removed.
+    struct Goodbye {
+        removed: Arc<Mutex<Vec<Error>>>,
+    }
+
+    /// Holds every write back until flushed with an empty one, as a full socket buffer would
+    /// until the peer reads.
+    struct Backlogged {
+        conn: Conn,
+        held: Vec<u8>,
+    }
+
+    impl AsRawFd for Backlogged {
+        fn as_raw_fd(&self) -> RawFd {
+            self.conn.as_raw_fd()
+        }
+    }
+
+    impl Stream for Backlogged {
+        fn recv(&mut self) -> Result<Vec<Vec<u8>>, Error> {
+            self.conn.recv()
+        }
+
+        fn send(&mut self, buf: &[u8]) -> Result<(), Error> {
+            if buf.is_empty() {
+                self.conn.send(&self.held)?;
+                self.held.clear();
+                return Ok(());
+            }
+
+            self.held.extend_from_slice(buf);
+            Err(Error::from(ErrorKind::WouldBlock))
+        }
+
+        fn shutdown(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    impl Handler for Goodbye {
+        fn on_server_created(&mut self, _fd: RawFd) {}
+
+        // Handler hands streams out as Arc<UnsafeCell<_>>, which is never Sync
+        #[allow(clippy::arc_with_non_send_sync)]
+        fn on_new_connection(&mut self, fd: RawFd, _: ListenerId) -> Arc<UnsafeCell<dyn Stream>> {
+            let stream = unsafe { UnixStream::from_raw_fd(fd) };
+            Arc::new(UnsafeCell::new(Backlogged {
+                conn: Conn(ManuallyDrop::new(stream)),
+                held: Vec::new(),
+            }))
+        }
+
+        fn on_data_received(&mut self, _socket: HydrogenSocket, _buf: Vec<u8>) {}
+
+        fn on_connection_removed(&mut self, _fd: RawFd, err: Error) {
+            self.removed.lock().unwrap().push(err);
+        }
+
+        fn on_connection_draining(&mut self, socket: HydrogenSocket) {
+            socket.send(b"bye");
+        }
+    }
+
+    #[test]
+    fn event_loop_runs_on_the_injected_poller() {
+        let script = Arc::new(Script::default());
+        let removed = Arc::new(AtomicUsize::new(0));
+        let server = start(Box::new(Echo { removed: removed.clone() }), &script);
+
+        let (mut client, _, token) = script.connect();
+        assert_eq!(server.stats().accepted, 1);
+
+        client.write_all(b"ping").unwrap();
+        script.report(libc::EPOLLIN, token);
+        let mut buf = [0u8; 4];
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+
+        drop(client);
+        script.report(libc::EPOLLIN | libc::EPOLLRDHUP, token);
+        wait_until(|| removed.load(Ordering::SeqCst) > 0);
+        assert_eq!(removed.load(Ordering::SeqCst), 1);
+        assert_eq!(server.stats().connections, 0);
+
+        server.shutdown();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn failing_to_start_removes_the_socket_files_bound() {
+        let busy = TcpListener::bind("127.0.0.1:0").unwrap();
+        let path = env::temp_dir().join(format!("hydrogen-failed-start-{}.sock", process::id()));
+        let cfg = Config::builder()
+            .addr("127.0.0.1:0")
+            .listen_unix(path.clone(), None)
+            .listen_tcp(busy.local_addr().unwrap())
+            .max_threads(1)
+            .build()
+            .unwrap();
+        let handler = Box::new(Echo { removed: Arc::new(AtomicUsize::new(0)) });
+
+        match begin(handler, cfg, Vec::new()) {
+            Err(HydrogenError::Bind(ref err)) if err.kind() == ErrorKind::AddrInUse => {}
+            Err(err) => panic!("expected AddrInUse, got {}", err),
+            Ok(_) => panic!("bound a port already in use"),
+        }
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn drain_flushes_the_goodbye_before_closing() {
+        let script = Arc::new(Script::default());
+        let removed = Arc::new(Mutex::new(Vec::new()));
+        let server = start(Box::new(Goodbye { removed: removed.clone() }), &script);
+        let (mut client, conn_fd, token) = script.connect();
+
+        let started = Instant::now();
+        server.drain(Duration::from_secs(5));
+
+        // The goodbye is held back until the connection is reported writable
+        script.wait_for_rearm(conn_fd, libc::EPOLLOUT);
+        assert!(removed.lock().unwrap().is_empty());
+        script.report(libc::EPOLLOUT, token);
+
+        let mut buf = [0u8; 3];
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"bye");
+
+        server.join().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        let removed = removed.lock().unwrap();
+        assert_eq!(removed.len(), 1);
+        assert!(is_server_shutdown(&removed[0]));
+    }
+
+    #[test]
+    fn drain_closes_what_is_left_at_the_deadline() {
+        let script = Arc::new(Script::default());
+        let removed = Arc::new(Mutex::new(Vec::new()));
+        let server = start(Box::new(Goodbye { removed: removed.clone() }), &script);
+        let (mut client, conn_fd, _) = script.connect();
+
+        // Never reported writable, so the goodbye never leaves
+        let started = Instant::now();
+        server.drain(Duration::from_millis(200));
+        script.wait_for_rearm(conn_fd, libc::EPOLLOUT);
+
+        server.join().unwrap();
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(200), "drained for {:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(5), "drained for {:?}", elapsed);
+        let removed = removed.lock().unwrap();
+        assert_eq!(removed.len(), 1);
+        assert!(is_server_shutdown(&removed[0]));
+
+        let mut buf = Vec::new();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(client.read_to_end(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn paused_listeners_accept_nothing_until_resumed() {
+        #[allow(unused_mut)]
+        let mut backends = vec![Backend::Epoll, Backend::Poll];
+        #[cfg(feature = "io-uring")]
+        backends.push(Backend::IoUring);
+
+        for &backend in backends.iter() {
+            for &accept in [Accept::Thread, Accept::EventLoop].iter() {
+                pause_and_resume(backend, accept);
+            }
+        }
+    }
+
+    fn pause_and_resume(backend: Backend, accept: Accept) {
+        let name = format!("hydrogen-pause-{}-{:?}-{:?}.sock", process::id(), backend, accept);
+        let path = env::temp_dir().join(name);
+        let listener = UnixListener::bind(&path).unwrap();
+        let gate = Arc::new(Gate::default());
+        let cfg = Config::builder()
+            .backend(backend)
+            .accept(accept)
+            .accept_batch(1)
+            .epoll_timeout(Duration::from_millis(10))
+            .max_threads(1)
+            .build()
+            .unwrap();
+        let handler = Box::new(Gated {
+            echo: Echo { removed: Arc::new(AtomicUsize::new(0)) },
+            gate: gate.clone(),
+        });
+        let server = begin(handler, cfg, vec![Listener::Unix(listener)]).unwrap();
+
+        // The first connection holds up accepting, so the rest are still waiting wherever the
+        // poller keeps them when the pause takes effect
+        let mut clients = (0..8).map(|_| say_hi(&path)).collect::<Vec<_>>();
+        assert!(wait_until(|| gate.entered.load(Ordering::SeqCst)));
+        ::std::thread::sleep(Duration::from_millis(50));
+        server.pause_accept();
+        gate.open();
+        ::std::thread::sleep(Duration::from_millis(50));
+
+        let mut late = say_hi(&path);
+        let mut buf = [0u8; 2];
+        late.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        let read = late.read_exact(&mut buf);
+        assert!(read.is_err(), "{:?} {:?} served a connection while paused", backend, accept);
+        assert!(server.stats().accepted < 9);
+
+        server.resume_accept();
+        clients.push(late);
+        for client in clients.iter_mut() {
+            client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            client.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, b"hi");
+        }
+        assert_eq!(server.stats().accepted, 9);
+
+        server.shutdown();
+        server.join().unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "io-uring")]
+    #[test]
+    fn completion_io_streams_echo_through_io_uring() {
+        let name = format!("hydrogen-completion-{}.sock", process::id());
+        let path = env::temp_dir().join(name);
+        let listener = UnixListener::bind(&path).unwrap();
+        let cfg = Config::builder()
+            .backend(Backend::IoUring)
+            .epoll_timeout(Duration::from_millis(10))
+            .max_threads(2)
+            .build()
+            .unwrap();
+        let removed = Arc::new(AtomicUsize::new(0));
+        let handler = Box::new(LineEcho { removed: removed.clone() });
+        let server = begin(handler, cfg, vec![Listener::Unix(listener)]).unwrap();
+
+        // Far more than one provided buffer, or one send, holds
+        let lines = (0..20_000).map(|n| format!("line {}\n", n)).collect::<String>();
+        let mut client = UnixStream::connect(&path).unwrap();
+        let mut writer = client.try_clone().unwrap();
+        let sent = lines.clone();
+        let writing = ::std::thread::spawn(move || writer.write_all(sent.as_bytes()).unwrap());
+
+        let mut echoed = vec![0u8; lines.len()];
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.read_exact(&mut echoed).unwrap();
+        writing.join().unwrap();
+        assert!(echoed == lines.as_bytes());
+
+        // The peer closing is reported like any other
+        drop(client);
+        assert!(wait_until(|| removed.load(Ordering::SeqCst) == 1));
+
+        server.shutdown();
+        server.join().unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+
+    /// Echoes each line back, over connections opted into completion I/O.
+    #[cfg(feature = "io-uring")]
+    struct LineEcho {
+        removed: Arc<AtomicUsize>,
+    }
+
+    /// Splits what the poller received into lines, never reading or writing itself.
+    #[cfg(feature = "io-uring")]
+    struct Lines {
+        conn: Conn,
+        partial: Vec<u8>,
+    }
+
+    #[cfg(feature = "io-uring")]
+    impl AsRawFd for Lines {
+        fn as_raw_fd(&self) -> RawFd {
+            self.conn.as_raw_fd()
+        }
+    }
+
+    #[cfg(feature = "io-uring")]
+    impl Stream for Lines {
+        fn recv(&mut self) -> Result<Vec<Vec<u8>>, Error> {
+            panic!("recv called on a completion I/O stream");
+        }
+
+        fn send(&mut self, _buf: &[u8]) -> Result<(), Error> {
+            panic!("send called on a completion I/O stream");
+        }
+
+        fn shutdown(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn completion_io(&self) -> bool {
+            true
+        }
+
+        fn decode(&mut self, buf: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+            self.partial.extend_from_slice(buf);
+            let mut lines = Vec::new();
+            while let Some(end) = self.partial.iter().position(|&b| b == b'\n') {
+                let rest = self.partial.split_off(end + 1);
+                lines.push(::std::mem::replace(&mut self.partial, rest));
+            }
+            Ok(lines)
+        }
+    }
+
+    #[cfg(feature = "io-uring")]
+    impl Handler for LineEcho {
+        fn on_server_created(&mut self, _fd: RawFd) {}
+
+        // Handler hands streams out as Arc<UnsafeCell<_>>, which is never Sync
+        #[allow(clippy::arc_with_non_send_sync)]
+        fn on_new_connection(&mut self, fd: RawFd, _: ListenerId) -> Arc<UnsafeCell<dyn Stream>> {
+            let stream = unsafe { UnixStream::from_raw_fd(fd) };
+            Arc::new(UnsafeCell::new(Lines {
+                conn: Conn(ManuallyDrop::new(stream)),
+                partial: Vec::new(),
+            }))
+        }
+
+        fn on_data_received(&mut self, socket: HydrogenSocket, buf: Vec<u8>) {
+            socket.send(&buf);
+        }
+
+        fn on_connection_removed(&mut self, _fd: RawFd, _err: Error) {
+            self.removed.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Connects to `path`, sending "hi" for the server to echo.
+    fn say_hi(path: &Path) -> UnixStream {
+        let mut client = UnixStream::connect(path).unwrap();
+        client.write_all(b"hi").unwrap();
+        client
+    }
+}
@@ -0,0 +1,119 @@
+// Copyright 2015 Nathan Sizemore <nathanrsizemore@gmail.com>
+//
+// This Source Code Form is subject to the terms of the
+// Mozilla Public License, v. 2.0. If a copy of the MPL was not
+// distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+
+
+use std::mem;
+use std::ptr;
+use std::io::{Error, ErrorKind};
+use std::os::unix::io::RawFd;
+
+use libc;
+
+
+/// Signals delivered to `Handler::on_signal` when `Config::handle_signals` is set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    /// SIGTERM, drains the server by default
+    Term,
+    /// SIGINT, drains the server by default
+    Int,
+    /// SIGHUP, calls `Handler::on_reload` by default
+    Hup,
+    /// SIGUSR1, no default action
+    Usr1,
+    /// SIGUSR2, no default action
+    Usr2
+}
+
+const WATCHED: [Signal; 5] = [Signal::Term, Signal::Int, Signal::Hup, Signal::Usr1, Signal::Usr2];
+
+impl Signal {
+    /// Returns the signal's number.
+    pub fn as_raw(&self) -> libc::c_int {
+        match *self {
+            Signal::Term => libc::SIGTERM,
+            Signal::Int => libc::SIGINT,
+            Signal::Hup => libc::SIGHUP,
+            Signal::Usr1 => libc::SIGUSR1,
+            Signal::Usr2 => libc::SIGUSR2
+        }
+    }
+
+    fn from_raw(signo: libc::c_int) -> Option<Signal> {
+        WATCHED.iter().find(|s| s.as_raw() == signo).cloned()
+    }
+}
+
+/// signalfd receiving every `Signal`.
+pub struct SignalFd {
+    pub fd: RawFd
+}
+
+impl SignalFd {
+    /// Blocks the watched signals on the calling thread, so threads spawned from it inherit the
+    /// mask, and creates a non-blocking signalfd for them.
+    pub fn new() -> Result<SignalFd, Error> {
+        unsafe {
+            let mut set: libc::sigset_t = mem::zeroed();
+            libc::sigemptyset(&mut set);
+            for signal in WATCHED.iter() {
+                libc::sigaddset(&mut set, signal.as_raw());
+            }
+
+            let result = libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut());
+            if result != 0 {
+                return Err(Error::from_raw_os_error(result));
+            }
+
+            let fd = libc::signalfd(-1, &set, libc::SFD_NONBLOCK | libc::SFD_CLOEXEC);
+            if fd < 0 {
+                return Err(Error::last_os_error());
+            }
+
+            Ok(SignalFd { fd: fd })
+        }
+    }
+
+    /// Reads every pending signal.
+    pub fn read(&self) -> Vec<Signal> {
+        let mut signals = Vec::<Signal>::new();
+        loop {
+            let mut info: libc::signalfd_siginfo = unsafe { mem::zeroed() };
+            let size = mem::size_of::<libc::signalfd_siginfo>();
+            let result = unsafe {
+                libc::read(self.fd, &mut info as *mut _ as *mut libc::c_void, size)
+            };
+            if result < 0 {
+                let err = Error::last_os_error();
+                match err.kind() {
+                    ErrorKind::Interrupted => continue,
+                    ErrorKind::WouldBlock => {}
+                    _ => error!("Reading signalfd {}: {}", self.fd, err)
+                };
+                break;
+            }
+            if result as usize != size {
+                break;
+            }
+
+            match Signal::from_raw(info.ssi_signo as libc::c_int) {
+                Some(signal) => signals.push(signal),
+                None => warn!("Unexpected signal {} on signalfd", info.ssi_signo)
+            }
+        }
+
+        signals
+    }
+}
+
+impl Drop for SignalFd {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
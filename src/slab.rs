@@ -0,0 +1,176 @@
+// Copyright 2015 Nathan Sizemore <nathanrsizemore@gmail.com>
+//
+// This Source Code Form is subject to the terms of the
+// Mozilla Public License, v. 2.0. If a copy of the MPL was not
+// distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+
+
+use std::slice;
+
+
+/// Slab whose entries stay in their slot until removed, each addressed by a token.
+///
+/// A token holds the slot's index in its low 32 bits and the slot's generation in its high
+/// 32 bits. The generation is bumped on every removal, so a token outliving its entry never
+/// finds whatever reuses the slot. This is what lets `epoll_event.u64` carry a token, an
+/// event that was already waiting when its connection was removed is simply not found.
+///
+/// Indexes are bounded by the number of open fds, far below `u32::MAX`, so no token has the
+/// low 32 bits of the values the server reserves at the top of the range: `u64::MAX` and
+/// `u64::MAX - 1` for the signalfd and the event loop's waker, and `u64::MAX - 2` counting
+/// down by one per listener for the listeners the event loop accepts on.
+pub struct TokenSlab<T> {
+    slots: Vec<Slot<T>>,
+    /// Indexes of empty slots, reused before the slab grows
+    free: Vec<usize>
+}
+
+struct Slot<T> {
+    generation: u32,
+    entry: Option<T>
+}
+
+impl<T> TokenSlab<T> {
+    pub fn with_capacity(capacity: usize) -> TokenSlab<T> {
+        TokenSlab {
+            slots: Vec::with_capacity(capacity),
+            free: Vec::new()
+        }
+    }
+
+    /// Returns the token the next `insert` will return.
+    pub fn next_token(&self) -> u64 {
+        match self.free.last() {
+            Some(&index) => to_token(index, self.slots[index].generation),
+            None => to_token(self.slots.len(), 0)
+        }
+    }
+
+    /// Inserts `entry`, returning its token.
+    pub fn insert(&mut self, entry: T) -> u64 {
+        match self.free.pop() {
+            Some(index) => {
+                let slot = &mut self.slots[index];
+                slot.entry = Some(entry);
+                to_token(index, slot.generation)
+            }
+            None => {
+                self.slots.push(Slot { generation: 0, entry: Some(entry) });
+                to_token(self.slots.len() - 1, 0)
+            }
+        }
+    }
+
+    /// Returns the entry `token` was returned for, if it has not been removed since.
+    pub fn get(&self, token: u64) -> Option<&T> {
+        let (index, generation) = from_token(token);
+        match self.slots.get(index) {
+            Some(slot) if slot.generation == generation => slot.entry.as_ref(),
+            _ => None
+        }
+    }
+
+    /// Removes and returns the entry `token` was returned for, if it has not been removed
+    /// already.
+    pub fn remove(&mut self, token: u64) -> Option<T> {
+        let (index, generation) = from_token(token);
+        let slot = match self.slots.get_mut(index) {
+            Some(s) if s.generation == generation && s.entry.is_some() => s,
+            _ => return None
+        };
+
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free.push(index);
+
+        slot.entry.take()
+    }
+
+    pub fn iter<'a>(&'a self) -> Iter<'a, T> {
+        Iter { slots: self.slots.iter() }
+    }
+}
+
+/// Iterator over the entries of a `TokenSlab`, in slot order.
+pub struct Iter<'a, T: 'a> {
+    slots: slice::Iter<'a, Slot<T>>
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        for slot in self.slots.by_ref() {
+            if let Some(ref entry) = slot.entry {
+                return Some(entry);
+            }
+        }
+
+        None
+    }
+}
+
+fn to_token(index: usize, generation: u32) -> u64 {
+    ((generation as u64) << 32) | (index as u64 & 0xffff_ffff)
+}
+
+fn from_token(token: u64) -> (usize, u32) {
+    ((token & 0xffff_ffff) as usize, (token >> 32) as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{from_token, to_token, TokenSlab};
+
+    #[test]
+    fn insert_get_remove() {
+        let mut slab = TokenSlab::with_capacity(2);
+        let a = slab.insert("a");
+        let b = slab.insert("b");
+        assert!(a != b);
+        assert_eq!(slab.get(a), Some(&"a"));
+        assert_eq!(slab.get(b), Some(&"b"));
+
+        assert_eq!(slab.remove(a), Some("a"));
+        assert_eq!(slab.get(a), None);
+        assert_eq!(slab.remove(a), None);
+        assert_eq!(slab.iter().collect::<Vec<_>>(), vec![&"b"]);
+    }
+
+    #[test]
+    fn reused_slot_gets_a_new_generation() {
+        let mut slab = TokenSlab::with_capacity(1);
+        let old = slab.insert(1);
+        slab.remove(old);
+
+        let next = slab.next_token();
+        let new = slab.insert(2);
+        assert_eq!(new, next);
+        assert_eq!(new & 0xffff_ffff, old & 0xffff_ffff);
+        assert!(new != old);
+
+        // A token outliving its entry never finds what took its slot
+        assert_eq!(slab.get(old), None);
+        assert_eq!(slab.remove(old), None);
+        assert_eq!(slab.get(new), Some(&2));
+    }
+
+    #[test]
+    fn next_token_matches_insert() {
+        let mut slab = TokenSlab::with_capacity(0);
+        for x in 0..4 {
+            let next = slab.next_token();
+            assert_eq!(slab.insert(x), next);
+        }
+    }
+
+    #[test]
+    fn tokens_stay_clear_of_reserved_values() {
+        // Even on its last generation, a slot's token has its index in the low half
+        for index in 0..1024 {
+            let token = to_token(index, u32::MAX);
+            assert_eq!(from_token(token), (index, u32::MAX));
+            assert!(token < u64::MAX - 2 - 1024);
+        }
+    }
+}
@@ -0,0 +1,439 @@
+// Copyright 2015 Nathan Sizemore <nathanrsizemore@gmail.com>
+//
+// This Source Code Form is subject to the terms of the
+// Mozilla Public License, v. 2.0. If a copy of the MPL was not
+// distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+
+
+use std::cell::Cell;
+use std::cmp;
+use std::fs;
+use std::mem;
+use std::io::{Error, ErrorKind};
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::ptr;
+use std::time::Duration;
+
+use libc;
+
+use config::{Config, ConnectionOptions};
+
+
+/// A listening socket a server accepts connections from.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Listener {
+    /// TCP, over IPv4 or IPv6
+    Tcp(TcpListener),
+    /// Unix domain stream socket, bound to a path or in the abstract namespace
+    Unix(UnixListener)
+}
+
+impl Listener {
+    /// Takes ownership of a listening stream socket, picking the variant from its address
+    /// family.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be an open socket that nothing else owns, as it is closed once the returned
+    /// listener is dropped. It is left open if this fails.
+    pub unsafe fn from_raw_fd(fd: RawFd) -> Result<Listener, Error> {
+        match family(fd)? {
+            libc::AF_INET | libc::AF_INET6 => Ok(Listener::Tcp(TcpListener::from_raw_fd(fd))),
+            libc::AF_UNIX => Ok(Listener::Unix(UnixListener::from_raw_fd(fd))),
+            family => Err(Error::new(ErrorKind::InvalidInput,
+                                     format!("fd {} has unsupported address family {}",
+                                             fd,
+                                             family)))
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<(), Error> {
+        match *self {
+            Listener::Tcp(ref l) => l.set_nonblocking(nonblocking),
+            Listener::Unix(ref l) => l.set_nonblocking(nonblocking)
+        }
+    }
+
+    /// Accepts a connection, returning its fd, already non-blocking and close-on-exec. The
+    /// caller owns the fd.
+    pub fn accept_fd(&self) -> Result<RawFd, Error> {
+        let fd = unsafe {
+            libc::accept4(self.as_raw_fd(),
+                          ptr::null_mut(),
+                          ptr::null_mut(),
+                          libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC)
+        };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        Ok(fd)
+    }
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match *self {
+            Listener::Tcp(ref l) => l.as_raw_fd(),
+            Listener::Unix(ref l) => l.as_raw_fd()
+        }
+    }
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Listener {
+        Listener::Tcp(listener)
+    }
+}
+
+impl From<UnixListener> for Listener {
+    fn from(listener: UnixListener) -> Listener {
+        Listener::Unix(listener)
+    }
+}
+
+/// Creates a TCP socket for `addr`, binds it and starts listening.
+///
+/// Unlike `TcpListener::bind`, options that only apply before `bind` or `listen` can be set:
+/// those in `cfg` first, then anything `before_bind` sets on the fd.
+pub fn bind_tcp<F>(addr: &SocketAddr, cfg: &Config, before_bind: F) -> Result<TcpListener, Error>
+    where F: FnOnce(RawFd) -> Result<(), Error>
+{
+    let domain = match *addr {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6
+    };
+
+    let (storage, len) = to_sockaddr(addr);
+    let addr = &storage as *const _ as *const libc::sockaddr;
+    listen_on(domain, addr, len, cfg.backlog, |fd| {
+        set_int_opt(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, cfg.reuse_addr as libc::c_int)?;
+        if cfg.reuse_port {
+            set_int_opt(fd, libc::SOL_SOCKET, libc::SO_REUSEPORT, 1)?;
+        }
+
+        if domain == libc::AF_INET6 {
+            let v6_only = if cfg.dual_stack { 0 } else { 1 };
+            set_int_opt(fd, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY, v6_only)?;
+        }
+
+        if let Some(queue_len) = cfg.tcp_fastopen {
+            let queue_len = clamp_int(queue_len as u64);
+            set_int_opt(fd, libc::IPPROTO_TCP, libc::TCP_FASTOPEN, queue_len)?;
+        }
+        if let Some(timeout) = cfg.defer_accept {
+            let secs = clamp_int(timeout.as_secs());
+            set_int_opt(fd, libc::IPPROTO_TCP, libc::TCP_DEFER_ACCEPT, secs)?;
+        }
+
+        before_bind(fd)
+    }, |_| Ok(()))
+}
+
+/// Binds a Unix domain socket at `path` and starts listening, running `before_bind` on the
+/// fd first.
+///
+/// A socket file left behind by a process that is no longer listening on it is removed
+/// first. Anything else already at `path` is an `AddrInUse` error. If `mode` is passed, the
+/// socket file's permissions are set to it before listening, so nobody can connect while
+/// the file still has the permissions the umask left it with.
+pub fn bind_unix<F>(path: &Path, mode: Option<u32>, backlog: u32, before_bind: F)
+    -> Result<UnixListener, Error>
+    where F: FnOnce(RawFd) -> Result<(), Error>
+{
+    let bytes = path.as_os_str().as_bytes();
+    if bytes.contains(&0) {
+        return Err(Error::new(ErrorKind::InvalidInput,
+                              format!("{} contains a nul byte", path.display())));
+    }
+
+    remove_stale_socket(path)?;
+
+    // Paths are nul terminated, the zeroed sockaddr_un provides it
+    let (addr, len) = to_sockaddr_un(bytes, 0, 1)?;
+    let bound = Cell::new(false);
+    let set_mode = |_| {
+        bound.set(true);
+        match mode {
+            Some(mode) => fs::set_permissions(path, fs::Permissions::from_mode(mode)),
+            None => Ok(())
+        }
+    };
+    let result = listen_on(libc::AF_UNIX,
+                           &addr as *const _ as *const libc::sockaddr,
+                           len,
+                           backlog,
+                           before_bind,
+                           set_mode);
+    // The file is ours once bound
+    if result.is_err() && bound.get() {
+        let _ = fs::remove_file(path);
+    }
+
+    result
+}
+
+/// Binds a Unix domain socket in the abstract namespace, which has no file to clean up or
+/// set permissions on, and starts listening.
+pub fn bind_abstract_unix<F>(name: &str, backlog: u32, before_bind: F)
+    -> Result<UnixListener, Error>
+    where F: FnOnce(RawFd) -> Result<(), Error>
+{
+    // Abstract names start with a nul byte and are not terminated
+    let (addr, len) = to_sockaddr_un(name.as_bytes(), 1, 0)?;
+    let addr = &addr as *const _ as *const libc::sockaddr;
+    listen_on(libc::AF_UNIX, addr, len, backlog, before_bind, |_| Ok(()))
+}
+
+/// Creates a stream socket in `domain`, hands it to `before_bind`, then binds it to `addr`,
+/// hands it to `after_bind` and listens with `backlog`. Connections are refused until then.
+fn listen_on<T, F, A>(domain: libc::c_int,
+                      addr: *const libc::sockaddr,
+                      len: libc::socklen_t,
+                      backlog: u32,
+                      before_bind: F,
+                      after_bind: A) -> Result<T, Error>
+    where T: FromRawFd + AsRawFd,
+          F: FnOnce(RawFd) -> Result<(), Error>,
+          A: FnOnce(RawFd) -> Result<(), Error>
+{
+    let fd = unsafe { libc::socket(domain, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    // Owns the fd from here on, so every early return closes it
+    let listener = unsafe { T::from_raw_fd(fd) };
+
+    before_bind(fd)?;
+
+    let result = unsafe { libc::bind(listener.as_raw_fd(), addr, len) };
+    if result < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    after_bind(fd)?;
+
+    let result = unsafe { libc::listen(listener.as_raw_fd(), clamp_int(backlog as u64)) };
+    if result < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    Ok(listener)
+}
+
+fn remove_stale_socket(path: &Path) -> Result<(), Error> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(m) => m,
+        Err(ref err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err)
+    };
+
+    if !metadata.file_type().is_socket() {
+        return Err(Error::new(ErrorKind::AddrInUse,
+                              format!("{} exists and is not a socket", path.display())));
+    }
+
+    // Only stale if nobody answers
+    match UnixStream::connect(path) {
+        Ok(_) => Err(Error::new(ErrorKind::AddrInUse,
+                                format!("{} is in use by another process", path.display()))),
+        Err(ref err) if err.kind() == ErrorKind::ConnectionRefused => {
+            debug!("Removing stale socket file: {}", path.display());
+            fs::remove_file(path)
+        }
+        Err(err) => Err(err)
+    }
+}
+
+/// Sets `opts` on the accepted connection `fd`. Only the buffer sizes and `linger` are set
+/// on Unix domain connections.
+pub fn set_connection_options(fd: RawFd, opts: &ConnectionOptions) -> Result<(), Error> {
+    // Nothing to set, spare the accept path a getsockname
+    if *opts == ConnectionOptions::default() {
+        return Ok(());
+    }
+
+    let family = family(fd)?;
+    let is_tcp = family == libc::AF_INET || family == libc::AF_INET6;
+
+    // (level, option, name for errors, value) of every int valued option to set
+    let mut int_opts = Vec::<(libc::c_int, libc::c_int, &str, libc::c_int)>::new();
+    if let Some(size) = opts.recv_buffer_size {
+        int_opts.push((libc::SOL_SOCKET, libc::SO_RCVBUF, "SO_RCVBUF", clamp_int(size as u64)));
+    }
+    if let Some(size) = opts.send_buffer_size {
+        int_opts.push((libc::SOL_SOCKET, libc::SO_SNDBUF, "SO_SNDBUF", clamp_int(size as u64)));
+    }
+    if is_tcp {
+        if let Some(nodelay) = opts.nodelay {
+            let value = nodelay as libc::c_int;
+            int_opts.push((libc::IPPROTO_TCP, libc::TCP_NODELAY, "TCP_NODELAY", value));
+        }
+        if let Some(keepalive) = opts.keepalive {
+            let value = keepalive as libc::c_int;
+            int_opts.push((libc::SOL_SOCKET, libc::SO_KEEPALIVE, "SO_KEEPALIVE", value));
+        }
+        if let Some(idle) = opts.keepalive_idle {
+            let value = clamp_int(idle.as_secs());
+            int_opts.push((libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, "TCP_KEEPIDLE", value));
+        }
+        if let Some(interval) = opts.keepalive_interval {
+            let value = clamp_int(interval.as_secs());
+            int_opts.push((libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, "TCP_KEEPINTVL", value));
+        }
+        if let Some(count) = opts.keepalive_count {
+            let value = clamp_int(count as u64);
+            int_opts.push((libc::IPPROTO_TCP, libc::TCP_KEEPCNT, "TCP_KEEPCNT", value));
+        }
+        if let Some(timeout) = opts.user_timeout {
+            let value = clamp_int(timeout.as_millis() as u64);
+            int_opts.push((libc::IPPROTO_TCP, libc::TCP_USER_TIMEOUT, "TCP_USER_TIMEOUT", value));
+        }
+        if let Some(tos) = opts.tos {
+            if family == libc::AF_INET6 {
+                let value = tos as libc::c_int;
+                int_opts.push((libc::IPPROTO_IPV6, libc::IPV6_TCLASS, "IPV6_TCLASS", value));
+            } else {
+                int_opts.push((libc::IPPROTO_IP, libc::IP_TOS, "IP_TOS", tos as libc::c_int));
+            }
+        }
+    }
+
+    for &(level, name, opt_name, value) in int_opts.iter() {
+        if let Err(err) = set_int_opt(fd, level, name, value) {
+            return Err(option_error(opt_name, err));
+        }
+    }
+
+    if let Some(linger) = opts.linger {
+        set_linger(fd, linger)?;
+    }
+
+    if is_tcp {
+        if let Some(ref name) = opts.congestion_control {
+            let result = unsafe {
+                libc::setsockopt(fd,
+                                 libc::IPPROTO_TCP,
+                                 libc::TCP_CONGESTION,
+                                 name.as_ptr() as *const libc::c_void,
+                                 name.len() as libc::socklen_t)
+            };
+            if result < 0 {
+                return Err(option_error("TCP_CONGESTION", Error::last_os_error()));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn set_linger(fd: RawFd, linger: Duration) -> Result<(), Error> {
+    let value = libc::linger { l_onoff: 1, l_linger: clamp_int(linger.as_secs()) };
+    let result = unsafe {
+        libc::setsockopt(fd,
+                         libc::SOL_SOCKET,
+                         libc::SO_LINGER,
+                         &value as *const _ as *const libc::c_void,
+                         mem::size_of::<libc::linger>() as libc::socklen_t)
+    };
+    if result < 0 {
+        return Err(option_error("SO_LINGER", Error::last_os_error()));
+    }
+
+    Ok(())
+}
+
+fn option_error(opt_name: &str, err: Error) -> Error {
+    Error::new(err.kind(), format!("Setting {}: {}", opt_name, err))
+}
+
+/// Returns the address family `fd` is bound in.
+fn family(fd: RawFd) -> Result<libc::c_int, Error> {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockname(fd, &mut storage as *mut _ as *mut libc::sockaddr, &mut len)
+    };
+    if result < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    Ok(storage.ss_family as libc::c_int)
+}
+
+/// Sets an `int` valued socket option.
+pub fn set_int_opt(fd: RawFd, level: libc::c_int, name: libc::c_int, value: libc::c_int)
+    -> Result<(), Error>
+{
+    let result = unsafe {
+        libc::setsockopt(fd,
+                         level,
+                         name,
+                         &value as *const _ as *const libc::c_void,
+                         mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if result < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Caps `value` at what fits in a `c_int` option.
+fn clamp_int(value: u64) -> libc::c_int {
+    cmp::min(value, libc::c_int::MAX as u64) as libc::c_int
+}
+
+fn to_sockaddr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match *addr {
+        SocketAddr::V4(ref a) => {
+            let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = a.port().to_be();
+            sin.sin_addr = libc::in_addr { s_addr: u32::from(*a.ip()).to_be() };
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(ref a) => {
+            let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = a.port().to_be();
+            sin6.sin6_flowinfo = a.flowinfo();
+            sin6.sin6_addr = libc::in6_addr { s6_addr: a.ip().octets() };
+            sin6.sin6_scope_id = a.scope_id();
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+
+    (storage, len as libc::socklen_t)
+}
+
+/// Builds a `sockaddr_un` for `name`, preceded by `prefix` and followed by `suffix` zeroed
+/// bytes of `sun_path`.
+fn to_sockaddr_un(name: &[u8], prefix: usize, suffix: usize)
+    -> Result<(libc::sockaddr_un, libc::socklen_t), Error>
+{
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+
+    let path_len = prefix + name.len() + suffix;
+    if path_len > addr.sun_path.len() {
+        return Err(Error::new(ErrorKind::InvalidInput, "Unix socket name is too long"));
+    }
+
+    for (x, byte) in name.iter().enumerate() {
+        addr.sun_path[prefix + x] = *byte as libc::c_char;
+    }
+
+    let len = mem::size_of::<libc::sa_family_t>() + path_len;
+
+    Ok((addr, len as libc::socklen_t))
+}
@@ -0,0 +1,21 @@
+// Copyright 2015 Nathan Sizemore <nathanrsizemore@gmail.com>
+//
+// This Source Code Form is subject to the terms of the
+// Mozilla Public License, v. 2.0. If a copy of the MPL was not
+// distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+
+
+use std::sync::{Mutex, MutexGuard};
+
+
+// Every test runs in the same process, so they all share its environment
+static ENV: Mutex<()> = Mutex::new(());
+
+/// Locks the environment for a test that changes it, or reads what other tests change.
+pub fn lock_env() -> MutexGuard<'static, ()> {
+    match ENV.lock() {
+        Ok(g) => g,
+        Err(p) => p.into_inner()
+    }
+}
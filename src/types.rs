@@ -8,21 +8,107 @@
 
 use std::io::{Error, ErrorKind};
 use std::cell::UnsafeCell;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::os::unix::io::{RawFd, AsRawFd};
+use std::time::{Duration, Instant};
 
 use libc;
-use simple_slab::Slab;
 
+use poller::Poller;
+use pool::ThreadPool;
+use slab::TokenSlab;
+use socket::Listener;
 use super::{Stream, Handler};
 
 
 /// Memory region for all concurrent connections.
 pub type ConnectionSlab = Arc<MutSlab>;
 /// Protected memory region for newly accepted connections.
-pub type NewConnectionSlab = Arc<Mutex<Slab<Connection>>>;
+pub type NewConnectionSlab = Arc<Mutex<Vec<Connection>>>;
 /// Queue of Connections needing various I/O operations.
-pub type IoQueue = Arc<Mutex<Vec<IoPair>>>;
+pub type IoQueue = Arc<EventQueue>;
+/// The listening sockets' fds, for as long as the listener thread has them open.
+pub type ListenerFds = Arc<Mutex<OpenListeners>>;
+
+pub struct OpenListeners {
+    pub fds: Vec<RawFd>,
+    /// Set once the fds were sent to another process, which then owns our socket files
+    pub handed_off: bool
+}
+
+/// Events waiting to be handed to the thread pool by the I/O Sentinel, which sleeps on
+/// `ready` until there are some.
+pub struct EventQueue {
+    pub pairs: Mutex<Vec<IoPair>>,
+    /// Notified when pairs are queued, or the queue is closed
+    ready: Condvar,
+    /// Set once the I/O Sentinel should exit. Only changed while holding `pairs`, so the
+    /// sentinel can not miss it between checking and waiting.
+    closed: AtomicBool
+}
+
+impl EventQueue {
+    pub fn new(capacity: usize) -> EventQueue {
+        EventQueue {
+            pairs: Mutex::new(Vec::with_capacity(capacity)),
+            ready: Condvar::new(),
+            closed: AtomicBool::new(false)
+        }
+    }
+
+    /// Moves every pair out of `pairs` onto the queue and wakes the I/O Sentinel.
+    pub fn push(&self, pairs: &mut Vec<IoPair>) {
+        if pairs.is_empty() {
+            return;
+        }
+
+        {
+            // Mutex lock
+            let mut queue = match self.pairs.lock() {
+                Ok(g) => g,
+                Err(p) => p.into_inner()
+            };
+            queue.append(pairs);
+        } // Mutex unlock
+
+        self.ready.notify_one();
+    }
+
+    /// Blocks until pairs are queued or the queue is closed, returning the locked queue.
+    pub fn wait<'a>(&'a self) -> MutexGuard<'a, Vec<IoPair>> {
+        let mut queue = match self.pairs.lock() {
+            Ok(g) => g,
+            Err(p) => p.into_inner()
+        };
+        while queue.is_empty() && !self.is_closed() {
+            queue = match self.ready.wait(queue) {
+                Ok(g) => g,
+                Err(p) => p.into_inner()
+            };
+        }
+
+        queue
+    }
+
+    /// Wakes the I/O Sentinel for good.
+    pub fn close(&self) {
+        {
+            // Mutex lock
+            let _queue = match self.pairs.lock() {
+                Ok(g) => g,
+                Err(p) => p.into_inner()
+            };
+            self.closed.store(true, Ordering::SeqCst);
+        } // Mutex unlock
+
+        self.ready.notify_all();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+}
 
 #[derive(Clone, PartialEq, Eq)]
 pub enum IoEvent {
@@ -42,23 +128,434 @@ pub struct IoPair {
     pub arc_connection: Arc<Connection>
 }
 
+/// Identifies which of a server's listeners accepted a connection, in the order they are
+/// listed in `Config`, handed over or passed by the service manager.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ListenerId(pub usize);
+
 pub struct Connection {
     /// Underlying file descriptor.
     pub fd: RawFd,
+    /// Key into the ConnectionSlab, also used as this connection's `epoll_event.u64`.
+    /// Set when moved from the NewConnectionSlab.
+    pub token: u64,
+    /// The listener this connection was accepted from.
+    pub listener: ListenerId,
     /// A Some(Error) options means this connection is in
     /// an error'd state and should be closed.
     pub err_mutex: Mutex<Option<Error>>,
     /// Mutex to ensure thread safe, ordered writes to our streams.
     /// They may have internal buffers
     pub tx_mutex: Mutex<()>,
+    /// Set while a write is waiting on EPOLLOUT to clear the stream's backlog.
+    pub write_backlog: AtomicBool,
+    /// Set once the event loop closed the fd. Held while the fd is written to or re-armed
+    /// from outside the event loop, so a `HydrogenSocket` kept past `on_connection_removed`
+    /// can not touch a newer connection that reused the fd number.
+    pub closed: Mutex<bool>,
+    /// Set once the poller took over receiving and sending, see `Stream::completion_io`.
+    pub completion_io: AtomicBool,
     /// Socket (Stream implemented trait-object).
     pub stream: Arc<UnsafeCell<dyn Stream>>
 }
 unsafe impl Send for Connection {}
 unsafe impl Sync for Connection {}
 
+/// Stop request shared between the `ServerHandle` and the server's threads.
+pub struct StopSignal {
+    /// Set once a stop has been requested
+    pub requested: AtomicBool,
+    /// Whether, and until when, connections should be drained before being closed
+    pub drain: Mutex<Drain>,
+    /// Wake each reactor's listener thread so it notices a stop request, indexed by reactor
+    pub listener_wakers: Vec<Waker>,
+    /// Registered with each reactor's epoll instance, wakes its event loop out of
+    /// `epoll_wait` for a stop request, new connections or connections to remove
+    pub event_loop_wakers: Vec<Waker>
+}
+
+impl StopSignal {
+    pub fn new(reactors: usize) -> Result<StopSignal, Error> {
+        let mut listener_wakers = Vec::<Waker>::with_capacity(reactors);
+        let mut event_loop_wakers = Vec::<Waker>::with_capacity(reactors);
+        for _ in 0..reactors {
+            listener_wakers.push(Waker::new()?);
+            event_loop_wakers.push(Waker::new()?);
+        }
+
+        Ok(StopSignal {
+            requested: AtomicBool::new(false),
+            drain: Mutex::new(Drain::Off),
+            listener_wakers: listener_wakers,
+            event_loop_wakers: event_loop_wakers
+        })
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// Stops the server without draining. Cuts short a drain in progress.
+    pub fn request_shutdown(&self) {
+        { // Mutex lock
+            let mut drain = match self.drain.lock() {
+                Ok(g) => g,
+                Err(p) => p.into_inner()
+            };
+            if *drain != Drain::Off {
+                *drain = Drain::Until(Instant::now());
+            }
+        } // Mutex unlock
+
+        self.request();
+    }
+
+    /// Stops the server after draining for no longer than `timeout`. Has no effect if a stop
+    /// was already requested.
+    pub fn request_drain(&self, timeout: Duration) {
+        { // Mutex lock
+            let mut drain = match self.drain.lock() {
+                Ok(g) => g,
+                Err(p) => p.into_inner()
+            };
+            if self.is_requested() {
+                return;
+            }
+            // A timeout too far out to represent never runs out
+            *drain = match Instant::now().checked_add(timeout) {
+                Some(deadline) => Drain::Until(deadline),
+                None => Drain::Unbounded
+            };
+        } // Mutex unlock
+
+        self.request();
+    }
+
+    /// Flags the stop and wakes the listener threads and event loops. Only the first call has
+    /// any effect.
+    pub fn request(&self) {
+        if self.requested.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        info!("Shutdown requested");
+        self.wake_all();
+    }
+
+    /// Wakes every listener thread and event loop, so they notice a change to the stop
+    /// request or to `Stats::accept_paused`.
+    pub fn wake_all(&self) {
+        for waker in self.listener_wakers.iter().chain(self.event_loop_wakers.iter()) {
+            waker.wake();
+        }
+    }
+
+    /// Returns whether, and until when, a drain was requested.
+    pub fn drain(&self) -> Drain {
+        match self.drain.lock() {
+            Ok(g) => *g,
+            Err(p) => *p.into_inner()
+        }
+    }
+}
+
+/// Drain requested of a stopping server, see `StopSignal::drain`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Drain {
+    /// Connections are closed straight away
+    Off,
+    /// Connections are drained until the deadline
+    Until(Instant),
+    /// Connections are drained until idle, however long that takes
+    Unbounded
+}
+
+/// A reactor's share of the I/O thread pool, which every reactor hands jobs to. Counts the
+/// reactor's own jobs until they finish, so it can wait on them without waiting on the
+/// others' traffic too.
+#[derive(Clone)]
+pub struct ReactorPool {
+    pool: ThreadPool,
+    jobs: Arc<PendingJobs>
+}
+
+struct PendingJobs {
+    /// Jobs queued or running
+    count: Mutex<usize>,
+    /// Notified when `count` drops to 0
+    idle: Condvar
+}
+
+/// Counts a job finished when dropped, even if the job panicked.
+struct JobDone(Arc<PendingJobs>);
+
+impl Drop for JobDone {
+    fn drop(&mut self) {
+        let mut count = match self.0.count.lock() {
+            Ok(g) => g,
+            Err(p) => p.into_inner()
+        };
+        *count -= 1;
+        if *count == 0 {
+            self.0.idle.notify_all();
+        }
+    }
+}
+
+impl ReactorPool {
+    pub fn new(pool: ThreadPool) -> ReactorPool {
+        ReactorPool {
+            pool: pool,
+            jobs: Arc::new(PendingJobs {
+                count: Mutex::new(0),
+                idle: Condvar::new()
+            })
+        }
+    }
+
+    /// Hands `job` to the pool. It is counted from before this returns.
+    pub fn execute<F>(&self, job: F) where F: FnOnce() + Send + 'static {
+        { // Mutex lock
+            let mut count = match self.jobs.count.lock() {
+                Ok(g) => g,
+                Err(p) => p.into_inner()
+            };
+            *count += 1;
+        } // Mutex unlock
+
+        let done = JobDone(self.jobs.clone());
+        self.pool.execute(move || {
+            let _done = done;
+            job();
+        });
+    }
+
+    /// Returns true if none of this reactor's jobs are queued or running.
+    pub fn is_idle(&self) -> bool {
+        match self.jobs.count.lock() {
+            Ok(g) => *g == 0,
+            Err(p) => *p.into_inner() == 0
+        }
+    }
+
+    /// Blocks until none of this reactor's jobs are queued or running.
+    pub fn join(&self) {
+        let mut count = match self.jobs.count.lock() {
+            Ok(g) => g,
+            Err(p) => p.into_inner()
+        };
+        while *count > 0 {
+            count = match self.jobs.idle.wait(count) {
+                Ok(g) => g,
+                Err(p) => p.into_inner()
+            };
+        }
+    }
+}
+
+/// State a running server reports through `ServerHandle::stats`.
+pub struct Stats {
+    /// Set while the listener thread leaves connections in the kernel's backlog
+    pub accept_paused: AtomicBool,
+    /// Connections handed to the handler since the server started
+    pub accepted: AtomicU64,
+    /// Connections currently open
+    pub connections: AtomicUsize
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats {
+            accept_paused: AtomicBool::new(false),
+            accepted: AtomicU64::new(0),
+            connections: AtomicUsize::new(0)
+        }
+    }
+}
+
+/// Non-blocking eventfd used to wake a thread blocked in `poll` or `epoll_wait`.
+pub struct Waker {
+    pub fd: RawFd
+}
+
+impl Waker {
+    pub fn new() -> Result<Waker, Error> {
+        let result = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if result < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        Ok(Waker { fd: result })
+    }
+
+    /// Makes the eventfd readable until `reset` is called.
+    pub fn wake(&self) {
+        let buf = 1u64;
+        let result = unsafe {
+            libc::write(self.fd, &buf as *const u64 as *const libc::c_void, 8)
+        };
+        if result < 0 {
+            let err = Error::last_os_error();
+            // WouldBlock means the counter is already saturated, the
+            // waiting side is going to wake anyway.
+            if err.kind() != ErrorKind::WouldBlock {
+                error!("Writing to eventfd {}: {}", self.fd, err);
+            }
+        }
+    }
+
+    /// Clears any pending wake.
+    pub fn reset(&self) {
+        let mut buf = 0u64;
+        unsafe {
+            libc::read(self.fd, &mut buf as *mut u64 as *mut libc::c_void, 8);
+        }
+    }
+}
+
+impl Drop for Waker {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// A reactor's `Poller`, with the events its connections are registered with. Each reactor
+/// has its own, shared by its threads and by every `HydrogenSocket` it hands out, and closed
+/// once the last of them lets go of it.
+pub struct Selector {
+    poller: Box<dyn Poller>,
+    /// Events every connection is registered and re-armed with
+    events: i32
+}
+
+impl Selector {
+    pub fn new(poller: Box<dyn Poller>, events: i32) -> Selector {
+        Selector { poller: poller, events: events }
+    }
+
+    /// Adds `fd` to the interest list, reporting `events` with `token` as `epoll_event.u64`.
+    pub fn add(&self, fd: RawFd, events: i32, token: u64) -> Result<(), Error> {
+        self.poller.add(fd, events, token)
+    }
+
+    /// Removes `fd` from the interest list.
+    pub fn delete(&self, fd: RawFd) -> Result<(), Error> {
+        self.poller.delete(fd)
+    }
+
+    /// Adds the listening socket `fd` to the interest list, reporting `events` with `token`
+    /// whenever connections are waiting on it.
+    pub fn add_listener(&self, fd: RawFd, events: i32, token: u64) -> Result<(), Error> {
+        self.poller.add_listener(fd, events, token)
+    }
+
+    /// Accepts a connection on `listener`, taking it from those the poller accepted if it
+    /// accepts on `listener` itself.
+    pub fn accept(&self, listener: &Listener) -> Result<RawFd, Error> {
+        match self.poller.accept(listener.as_raw_fd()) {
+            Some(result) => result,
+            None => listener.accept_fd()
+        }
+    }
+
+    /// Takes the connections the poller accepted on `listener` before it was deleted, but
+    /// never handed out.
+    pub fn take_accepted(&self, listener: &Listener) -> Vec<RawFd> {
+        self.poller.take_accepted(listener.as_raw_fd())
+    }
+
+    /// Tells the poller `fd` is about to be closed.
+    pub fn closing(&self, fd: RawFd) {
+        self.poller.closing(fd)
+    }
+
+    /// Adds a new connection to the interest list. On failure the connection is put in an
+    /// error'd state.
+    pub fn add_connection(&self, arc_connection: &Arc<Connection>) {
+        let fd = arc_connection.fd;
+        debug!("Adding fd {} to poller", fd);
+
+        let stream_ptr = arc_connection.stream.get();
+        let added = if unsafe { (*stream_ptr).completion_io() } {
+            self.poller.add_stream(fd, self.events, arc_connection.token)
+        } else {
+            self.poller.add(fd, self.events, arc_connection.token).map(|_| false)
+        };
+        match added {
+            Ok(completion_io) => {
+                arc_connection.completion_io.store(completion_io, Ordering::SeqCst);
+            }
+            Err(err) => {
+                error!("Adding fd: {} to poller:   {}", fd, err);
+
+                let mut err_state = match arc_connection.err_mutex.lock() {
+                    Ok(g) => g,
+                    Err(p) => p.into_inner()
+                };
+                *err_state = Some(err);
+            }
+        }
+    }
+
+    /// Takes what the poller received on a connection opted into completion I/O.
+    pub fn recv(&self, fd: RawFd) -> Result<Vec<u8>, Error> {
+        self.poller.recv(fd)
+    }
+
+    /// Queues `buf` to be sent by the poller on a connection opted into completion I/O.
+    pub fn send(&self, fd: RawFd, buf: Vec<u8>) -> Result<(), Error> {
+        self.poller.send(fd, buf)
+    }
+
+    /// Returns whether the poller has anything left to send on `fd`.
+    pub fn sending(&self, fd: RawFd) -> bool {
+        self.poller.sending(fd)
+    }
+
+    /// Re-arms a connection in the interest list with the event mask, unless it was closed.
+    /// On failure the connection is put in an error'd state.
+    pub fn rearm(&self, arc_connection: &Arc<Connection>, flags: i32) {
+        let closed = match arc_connection.closed.lock() {
+            Ok(g) => g,
+            Err(p) => p.into_inner()
+        };
+        if *closed {
+            trace!("Not re-arming closed fd: {}", arc_connection.fd);
+            return;
+        }
+
+        self.rearm_open(arc_connection, flags);
+    }
+
+    /// Re-arms a connection the caller holds `Connection::closed` for and found open.
+    fn rearm_open(&self, arc_connection: &Arc<Connection>, flags: i32) {
+        let fd = arc_connection.fd;
+
+        trace!("Re-arming   fd: {}    flags: {:#b}", fd, (flags as u32));
+
+        let token = arc_connection.token;
+        if let Err(err) = self.poller.modify(fd, self.events | flags, token) {
+            error!("Re-arming   fd: {}    {}", fd, err);
+
+            let mut err_state = match arc_connection.err_mutex.lock() {
+                Ok(g) => g,
+                Err(p) => p.into_inner()
+            };
+            *err_state = Some(err);
+        }
+    }
+
+    /// Waits up to `timeout` milliseconds for events, returning how many were written to
+    /// `buf`.
+    pub fn wait(&self, buf: &mut [libc::epoll_event], timeout: i32) -> Result<usize, Error> {
+        self.poller.wait(buf, timeout)
+    }
+}
+
 pub struct MutSlab {
-    pub inner: UnsafeCell<Slab<Arc<Connection>>>
+    pub inner: UnsafeCell<TokenSlab<Arc<Connection>>>
 }
 unsafe impl Send for MutSlab {}
 unsafe impl Sync for MutSlab {}
@@ -80,32 +577,41 @@ impl Clone for EventHandler {
 pub struct HydrogenSocket {
     /// The connection this socket represents
     pub arc_connection: Arc<Connection>,
-    /// Function responsible for re-arming fd in epoll instance
-    rearm_fn: unsafe fn(&Arc<Connection>, i32)
+    /// Selector of the reactor the connection belongs to
+    epoll: Arc<Selector>
 }
 
 impl Clone for HydrogenSocket {
     fn clone(&self) -> HydrogenSocket {
-        let fn_ptr = self.rearm_fn;
         HydrogenSocket {
             arc_connection: self.arc_connection.clone(),
-            rearm_fn: fn_ptr
+            epoll: self.epoll.clone()
         }
     }
 }
 
 impl HydrogenSocket {
-    pub fn new(arc_connection: Arc<Connection>,
-               rearm_fn: unsafe fn(&Arc<Connection>, i32))
-               -> HydrogenSocket
-    {
+    pub fn new(arc_connection: Arc<Connection>, epoll: Arc<Selector>) -> HydrogenSocket {
         HydrogenSocket {
             arc_connection: arc_connection,
-            rearm_fn: rearm_fn
+            epoll: epoll
         }
     }
 
+    /// Sends `buf` through the connection's stream. Does nothing once the connection was
+    /// closed.
     pub fn send(&self, buf: &[u8]) {
+        // Mutex lock
+        // Held until re-armed, the fd is not closed and reused in between
+        let closed = match self.arc_connection.closed.lock() {
+            Ok(g) => g,
+            Err(p) => p.into_inner()
+        };
+        if *closed {
+            trace!("HydrogenSocket.send on closed fd: {}", self.arc_connection.fd);
+            return;
+        }
+
         let err;
         { // Mutex lock
             drop(match self.arc_connection.tx_mutex.lock() {
@@ -114,8 +620,15 @@ impl HydrogenSocket {
             });
 
             let stream_ptr = self.arc_connection.stream.get();
-            let write_result = unsafe {
-                (*stream_ptr).send(buf)
+            let write_result = if self.arc_connection.completion_io.load(Ordering::SeqCst) {
+                let fd = self.arc_connection.fd;
+                unsafe {
+                    (*stream_ptr).encode(buf).and_then(|b| self.epoll.send(fd, b))
+                }
+            } else {
+                unsafe {
+                    (*stream_ptr).send(buf)
+                }
             };
             if write_result.is_ok() {
                 trace!("HydrogenSocket.send OK");
@@ -129,10 +642,9 @@ impl HydrogenSocket {
             ErrorKind::WouldBlock => {
                 trace!("HydrogenSocket.send received WouldBlock");
 
-                let execute = self.rearm_fn;
-                unsafe {
-                    execute(&(self.arc_connection), libc::EPOLLOUT);
-                }
+                self.arc_connection.write_backlog.store(true, Ordering::SeqCst);
+
+                self.epoll.rearm_open(&self.arc_connection, libc::EPOLLOUT);
             }
             _ => {
                 trace!("HydrogenSocket.send received err");
@@ -148,6 +660,11 @@ impl HydrogenSocket {
         }
     }
 
+    /// Returns the listener the connection was accepted from.
+    pub fn listener(&self) -> ListenerId {
+        self.arc_connection.listener
+    }
+
     pub fn shutdown(&mut self) -> Result<(), Error> {
         let stream_ptr = self.arc_connection.stream.get();
         unsafe {